@@ -0,0 +1,48 @@
+use lrchip8::chip8::Chip8;
+
+// Instructions run per input, about 15 seconds at the default speed.
+pub const TICKS: usize = 10_000;
+
+pub const ITERS_PER_FRAME: usize = 10;
+
+// Checks what must hold after every instruction, whatever the rom does.
+pub fn check_invariants(chip8: &Chip8) {
+    assert!(chip8.sp() <= 16, "SP out of the stack: {}", chip8.sp());
+    assert!(
+        (chip8.pc() as usize) < chip8.mem().len(),
+        "PC out of memory: 0x{:X}",
+        chip8.pc()
+    );
+    assert!(
+        chip8.video().iter().all(|&pixel| pixel <= 1),
+        "Pixel value other than 0 or 1"
+    );
+}
+
+// Runs ticks like the emulator's main loop, with the keys given for each
+// frame, checking the invariants. Stops once the rom halts, after checking
+// that it stays halted.
+pub fn run(chip8: &mut Chip8, mut keys: impl FnMut() -> u16) {
+    for tick in 0..TICKS {
+        let iter = tick % ITERS_PER_FRAME;
+
+        if iter == 0 {
+            let keys = keys();
+            chip8.update_timers();
+            chip8.write_keys(&std::array::from_fn::<_, 16, _>(|key| {
+                keys & (1 << key) != 0
+            }));
+        }
+
+        chip8.set_vblank(iter == 0);
+        chip8.tick();
+        check_invariants(chip8);
+
+        if chip8.fault().is_some() {
+            let state_hash = chip8.state_hash();
+            chip8.tick();
+            assert_eq!(chip8.state_hash(), state_hash, "Halted rom kept running");
+            break;
+        }
+    }
+}
@@ -0,0 +1,42 @@
+// Opcode streams, each opcode with the keys held while the frame it starts
+// runs.
+//
+// Input: quirks (1 byte), then 4 bytes per opcode: opcode and keys, both big
+// endian.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lrchip8::chip8::{Chip8, Quirks, MAX_ROM_SIZE};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&quirks, data)) = data.split_first() else {
+        return;
+    };
+
+    let words: Vec<_> = data
+        .chunks_exact(4)
+        .take(MAX_ROM_SIZE / 2)
+        .map(|bytes| {
+            (
+                u16::from_be_bytes([bytes[0], bytes[1]]),
+                u16::from_be_bytes([bytes[2], bytes[3]]),
+            )
+        })
+        .collect();
+
+    if words.is_empty() {
+        return;
+    }
+
+    let opcodes: Vec<_> = words.iter().map(|&(opcode, _)| opcode).collect();
+
+    let mut chip8 = Chip8::init(0);
+    chip8.set_quirks(Quirks::from_bits(quirks));
+    chip8.load16(&opcodes).unwrap();
+
+    let mut keys = words.iter().map(|&(_, keys)| keys).cycle();
+    common::run(&mut chip8, || keys.next().unwrap());
+});
@@ -0,0 +1,36 @@
+// Any bytes as a rom, with random keys.
+//
+// Input: RNG seed (8 bytes), quirks (1 byte), rom.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lrchip8::chip8::{Chip8, Quirks, MAX_ROM_SIZE};
+
+mod common;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((header, rom)) = data.split_first_chunk::<9>() else {
+        return;
+    };
+
+    let seed = u64::from_le_bytes(header[..8].try_into().unwrap());
+    let mut chip8 = Chip8::init(seed);
+    chip8.set_quirks(Quirks::from_bits(header[8]));
+
+    // Roms too big for memory are refused, not truncated.
+    if let Err(msg) = chip8.load(rom) {
+        assert!(rom.len() > MAX_ROM_SIZE, "Rom refused: {}", msg);
+        return;
+    }
+    assert!(rom.len() <= MAX_ROM_SIZE, "Oversized rom loaded");
+
+    // Xorshift, seeded from the input too.
+    let mut state = seed | 1;
+    common::run(&mut chip8, || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u16
+    });
+});
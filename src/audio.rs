@@ -1,58 +1,332 @@
+use std::{
+    collections::VecDeque,
+    sync::mpsc::{self, Receiver, Sender},
+};
+
 use sdl2::{
     audio::{AudioCallback, AudioDevice, AudioSpecDesired},
     AudioSubsystem,
 };
 
-struct SquareWave {
+const SAMPLE_RATE: i32 = 44100;
+
+const ATTACK_SECS: f32 = 0.002;
+const RELEASE_SECS: f32 = 0.005;
+
+// How far behind the emulated time the playback runs.
+const LATENCY_SECS: f64 = 0.05;
+// Playback falling further behind than this skips ahead.
+const MAX_LATENCY_SECS: f64 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    // Duty cycle in (0, 1).
+    Pulse(f32),
+    Triangle,
+}
+
+impl Waveform {
+    // Parses "square", "triangle", "pulse" or "pulse:<duty>".
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec.split_once(':').unwrap_or((spec, "")) {
+            ("square", "") => Ok(Waveform::Square),
+            ("triangle", "") => Ok(Waveform::Triangle),
+            ("pulse", "") => Ok(Waveform::Pulse(0.25)),
+            ("pulse", duty) => match duty.parse() {
+                Ok(duty) if duty > 0.0 && duty < 1.0 => Ok(Waveform::Pulse(duty)),
+                _ => Err(format!("Invalid pulse duty cycle: {}", duty)),
+            },
+            _ => Err(format!("Unknown waveform: {}", spec)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tone {
+    pub freq: f32,
+    pub volume: f32,
+    pub waveform: Waveform,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Self {
+            freq: 200.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+// Renders the buzzer signal, shaping on/off transitions with a short envelope.
+pub struct Beeper {
+    tone: Tone,
     phase_inc: f32,
     phase: f32,
-    volume: f32,
+    env: f32,
+    attack_inc: f32,
+    release_inc: f32,
 }
 
-impl AudioCallback for SquareWave {
-    type Channel = f32;
+impl Beeper {
+    pub fn init(tone: Tone, sample_rate: u32) -> Self {
+        let sample_rate = sample_rate as f32;
 
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave.
+        Self {
+            tone,
+            phase_inc: tone.freq / sample_rate,
+            phase: 0.0,
+            env: 0.0,
+            attack_inc: 1.0 / (ATTACK_SECS * sample_rate),
+            release_inc: 1.0 / (RELEASE_SECS * sample_rate),
+        }
+    }
+
+    pub fn render(&mut self, out: &mut [f32], on: bool) {
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
+            self.env = if on {
+                (self.env + self.attack_inc).min(1.0)
             } else {
-                -self.volume
+                (self.env - self.release_inc).max(0.0)
             };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+
+            *x = self.wave() * self.tone.volume * self.env;
+
+            // Restart the wave on silence so every beep sounds the same.
+            self.phase = if self.env > 0.0 {
+                (self.phase + self.phase_inc) % 1.0
+            } else {
+                0.0
+            };
+        }
+    }
+
+    fn wave(&self) -> f32 {
+        match self.tone.waveform {
+            Waveform::Square => {
+                if self.phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Pulse(duty) => {
+                if self.phase < duty {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            Waveform::Triangle => 1.0 - 4.0 * (self.phase - 0.5).abs(),
+        }
+    }
+}
+
+enum Message {
+    // The buzzer changed state at the given emulated time.
+    Edge(f64, bool),
+    // Everything up to the given emulated time has been produced.
+    Sync(f64),
+}
+
+// Consumer side of the audio stream, played on the SDL audio thread.
+struct Stream {
+    beeper: Beeper,
+    rx: Receiver<Message>,
+    secs_per_sample: f64,
+    edges: VecDeque<(f64, bool)>,
+    buzzer: bool,
+    cursor: Option<f64>,
+    horizon: f64,
+}
+
+impl Stream {
+    fn init(beeper: Beeper, rx: Receiver<Message>, sample_rate: u32) -> Self {
+        Self {
+            beeper,
+            rx,
+            secs_per_sample: 1.0 / sample_rate as f64,
+            edges: VecDeque::new(),
+            buzzer: false,
+            cursor: None,
+            horizon: 0.0,
+        }
+    }
+
+    fn receive(&mut self) {
+        for message in self.rx.try_iter() {
+            match message {
+                Message::Edge(time, on) => self.edges.push_back((time, on)),
+                Message::Sync(time) => self.horizon = time,
+            }
+        }
+    }
+
+    fn render(&mut self, out: &mut [f32]) {
+        self.receive();
+
+        let mut cursor = match self.cursor {
+            Some(cursor) if self.horizon - cursor <= MAX_LATENCY_SECS => cursor,
+            // Not started yet, or too far behind the emulator.
+            _ if self.horizon >= LATENCY_SECS => self.horizon - LATENCY_SECS,
+            _ => {
+                self.beeper.render(out, false);
+                return;
+            }
+        };
+
+        let mut pos = 0;
+
+        while pos < out.len() {
+            while let Some(&(time, on)) = self.edges.front() {
+                if time > cursor {
+                    break;
+                }
+
+                self.buzzer = on;
+                self.edges.pop_front();
+            }
+
+            // Underrun: fade out and wait for the emulator to catch up.
+            if cursor >= self.horizon {
+                self.beeper.render(&mut out[pos..], false);
+                break;
+            }
+
+            let next = self
+                .edges
+                .front()
+                .map_or(self.horizon, |&(time, _)| time.min(self.horizon));
+            let len = (((next - cursor) / self.secs_per_sample).ceil() as usize)
+                .clamp(1, out.len() - pos);
+
+            self.beeper.render(&mut out[pos..(pos + len)], self.buzzer);
+
+            pos += len;
+            cursor += len as f64 * self.secs_per_sample;
         }
+
+        self.cursor = Some(cursor);
+    }
+}
+
+impl AudioCallback for Stream {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        self.render(out);
     }
 }
 
 pub struct Audio {
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<Stream>,
+    tx: Sender<Message>,
+    buzzer: bool,
 }
 
 impl Audio {
-    pub fn init(audio_subsystem: AudioSubsystem) -> Self {
+    pub fn init(audio_subsystem: AudioSubsystem, tone: Tone) -> Self {
         let audio_spec = AudioSpecDesired {
-            freq: Some(44100),
+            freq: Some(SAMPLE_RATE),
             channels: Some(1),
             samples: None,
         };
 
+        let (tx, rx) = mpsc::channel();
+
         let device = audio_subsystem
-            .open_playback(None, &audio_spec, |spec| SquareWave {
-                phase_inc: 200.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
+            .open_playback(None, &audio_spec, |spec| {
+                let sample_rate = spec.freq as u32;
+
+                Stream::init(Beeper::init(tone, sample_rate), rx, sample_rate)
             })
             .unwrap();
 
-        Self { device }
+        device.resume();
+
+        Self {
+            device,
+            tx,
+            buzzer: false,
+        }
     }
 
-    pub fn play(&self, chip8_audio: bool) {
-        if chip8_audio {
-            self.device.resume();
-        } else {
-            self.device.pause();
+    // Records the buzzer state at the given emulated time, in seconds.
+    pub fn set_buzzer(&mut self, on: bool, time: f64) {
+        if on != self.buzzer {
+            self.buzzer = on;
+            self.tx.send(Message::Edge(time, on)).unwrap();
         }
     }
+
+    // Makes everything up to the given emulated time available for playback.
+    pub fn sync(&self, time: f64) {
+        self.tx.send(Message::Sync(time)).unwrap();
+    }
+}
+
+impl Drop for Audio {
+    fn drop(&mut self) {
+        self.device.pause();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use super::{Beeper, Message, Stream, Tone, Waveform};
+
+    #[test]
+    fn test_beeper_envelope() {
+        let mut beeper = Beeper::init(Tone::default(), 1000);
+        let mut out = [0f32; 4];
+
+        beeper.render(&mut out, true);
+        assert!(out[0] > 0.0 && out[0] < out[1]);
+
+        let mut out = [0f32; 8];
+
+        beeper.render(&mut out, false);
+        assert!(out[0] != 0.0);
+        assert_eq!(out[7], 0.0);
+        assert_eq!(beeper.phase, 0.0);
+    }
+
+    #[test]
+    fn test_beeper_triangle() {
+        let tone = Tone {
+            freq: 250.0,
+            volume: 1.0,
+            waveform: Waveform::Triangle,
+        };
+        let mut beeper = Beeper::init(tone, 1000);
+        beeper.env = 1.0;
+        let mut out = [0f32; 4];
+
+        beeper.render(&mut out, true);
+
+        assert_eq!(out, [-1.0, 0.0, 1.0, 0.0]);
+    }
+
+    #[test]
+    fn test_stream_underrun() {
+        let (tx, rx) = mpsc::channel();
+        let mut stream = Stream::init(Beeper::init(Tone::default(), 1000), rx, 1000);
+        let mut out = [0f32; 40];
+
+        tx.send(Message::Edge(0.0, true)).unwrap();
+        tx.send(Message::Sync(0.1)).unwrap();
+
+        stream.render(&mut out);
+        assert!(stream.buzzer);
+        assert!(out.iter().all(|&x| x != 0.0));
+
+        // Only 10 samples left: the beep fades out instead of clicking.
+        stream.render(&mut out);
+        assert!(out[..10].iter().all(|&x| x != 0.0));
+        assert!(out[20..].iter().all(|&x| x == 0.0));
+        assert!(stream.cursor.unwrap() >= 0.1);
+    }
 }
@@ -0,0 +1,186 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use gif::{Encoder, Frame, Repeat};
+use png::{BitDepth, ColorType};
+use sdl2::pixels::Color;
+
+const FPS: u64 = 60;
+
+enum Sink {
+    Gif(Encoder<BufWriter<File>>),
+    // Numbered files, one per distinct frame: <stem>_<frame>.png
+    Png(PathBuf),
+}
+
+// Records the framebuffer once per emulated frame, to an animated GIF or to a
+// PNG sequence. Repeated frames are not written again, they only extend the
+// delay of the previous one.
+pub struct VideoRecorder {
+    sink: Sink,
+    cols: usize,
+    rows: usize,
+    scale_factor: usize,
+    palette: Vec<u8>,
+    frame: u64,
+    // The last distinct frame and the frame number it was first seen at.
+    last: Option<(Vec<u8>, u64)>,
+}
+
+impl VideoRecorder {
+    // Records a GIF if the path ends in ".gif", a PNG sequence otherwise.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        cols: usize,
+        rows: usize,
+        scale_factor: usize,
+        colors: &[Color],
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let palette = colors
+            .iter()
+            .flat_map(|c| [c.r, c.g, c.b])
+            .collect::<Vec<u8>>();
+
+        let sink = if path.extension().is_some_and(|ext| ext == "gif") {
+            let writer = BufWriter::new(File::create(path)?);
+            let width = (cols * scale_factor) as u16;
+            let height = (rows * scale_factor) as u16;
+
+            let mut encoder =
+                Encoder::new(writer, width, height, &palette).map_err(io::Error::other)?;
+            encoder
+                .set_repeat(Repeat::Infinite)
+                .map_err(io::Error::other)?;
+
+            Sink::Gif(encoder)
+        } else {
+            Sink::Png(path.with_extension(""))
+        };
+
+        Ok(Self {
+            sink,
+            cols,
+            rows,
+            scale_factor,
+            palette,
+            frame: 0,
+            last: None,
+        })
+    }
+
+    pub fn record(&mut self, chip8_video: &[u8]) -> io::Result<()> {
+        let changed = self
+            .last
+            .as_ref()
+            .is_none_or(|(video, _)| video != chip8_video);
+
+        if changed {
+            if let Some((video, start)) = self.last.take() {
+                self.write(&video, start, self.frame)?;
+            }
+
+            self.last = Some((chip8_video.to_vec(), self.frame));
+        }
+
+        self.frame += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some((video, start)) = self.last.take() {
+            self.write(&video, start, self.frame)?;
+        }
+
+        if let Sink::Gif(encoder) = self.sink {
+            encoder.into_inner().map_err(io::Error::other)?.flush()?;
+        }
+
+        Ok(())
+    }
+
+    // Writes a frame shown from frame `start` until right before frame `end`.
+    fn write(&mut self, video: &[u8], start: u64, end: u64) -> io::Result<()> {
+        let width = self.cols * self.scale_factor;
+        let height = self.rows * self.scale_factor;
+
+        let pixels = (0..(width * height))
+            .map(|i| {
+                let col = (i % width) / self.scale_factor;
+                let row = (i / width) / self.scale_factor;
+
+                video[row * self.cols + col]
+            })
+            .collect::<Vec<u8>>();
+
+        match &mut self.sink {
+            Sink::Gif(encoder) => {
+                // GIF delays are in centiseconds: round frame boundaries
+                // instead of durations, so the error does not add up.
+                let delay = (end * 100 / FPS) - (start * 100 / FPS);
+
+                let mut frame =
+                    Frame::from_indexed_pixels(width as u16, height as u16, pixels, None);
+                frame.delay = delay as u16;
+
+                encoder.write_frame(&frame).map_err(io::Error::other)
+            }
+            Sink::Png(stem) => {
+                let mut file_name = stem.file_name().unwrap_or_default().to_owned();
+                file_name.push(format!("_{:06}.png", start));
+
+                let writer = BufWriter::new(File::create(stem.with_file_name(file_name))?);
+
+                let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+                encoder.set_color(ColorType::Indexed);
+                encoder.set_depth(BitDepth::Eight);
+                encoder.set_palette(self.palette.clone());
+
+                let mut writer = encoder.write_header().map_err(io::Error::other)?;
+                writer.write_image_data(&pixels).map_err(io::Error::other)?;
+                writer.finish().map_err(io::Error::other)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::File};
+
+    use sdl2::pixels::Color;
+
+    use super::VideoRecorder;
+
+    #[test]
+    fn test_gif_skips_duplicate_frames() {
+        let path = env::temp_dir().join("lrchip8_test_capture.gif");
+        let colors = [Color::BLACK, Color::WHITE];
+        let mut rec = VideoRecorder::create(&path, 2, 1, 2, &colors).unwrap();
+
+        rec.record(&[0, 1]).unwrap();
+        rec.record(&[0, 1]).unwrap();
+        rec.record(&[0, 1]).unwrap();
+        rec.record(&[1, 0]).unwrap();
+        rec.finish().unwrap();
+
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(&path).unwrap())
+            .unwrap();
+        let mut frames = Vec::new();
+
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], (5, vec![0, 0, 1, 1, 0, 0, 1, 1]));
+        assert_eq!(frames[1].0, 1);
+    }
+}
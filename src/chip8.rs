@@ -1,23 +1,35 @@
 // Reference: http://devernay.free.fr/hacks/chip8/C8TECH10.HTM
 
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+};
+
 use oorandom::Rand32;
 
-use crate::{dec_addr, dec_byte, dec_error, dec_nibble, dec_x, dec_y};
+use crate::{dec_addr, dec_byte, dec_error, dec_nibble, dec_x, dec_y, symbols::Symbols};
 
 const NUM_REGS: usize = 16;
 
+// The sizes below are those of the default machine. Chip8Builder makes others.
 const STACK_SIZE: usize = 16;
 
 const MEM_SIZE: usize = 4096;
 
-const ROM_START_ADDR: usize = 0x200;
+pub const ROM_START_ADDR: usize = 0x200;
 
 const VIDEO_COLS: usize = 64;
 const VIDEO_ROWS: usize = 32;
-const VIDEO_SIZE: usize = VIDEO_COLS * VIDEO_ROWS;
 
 const KEYS_SIZE: usize = 16;
 
+// Frames per second of the timers and the display.
+pub const FPS: u32 = 60;
+
+pub const MAX_ROM_SIZE: usize = MEM_SIZE - ROM_START_ADDR;
+
+const STATE_MAGIC: &[u8; 8] = b"LRC8STA1";
+
 const FONT_BYTES_PER_CHAR: u16 = 5;
 const FONT_SET: [u8; 80] = [
     0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -38,52 +50,499 @@ const FONT_SET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// Behaviours that differ between CHIP-8 platforms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    // 8xy1, 8xy2 and 8xy3 reset VF to 0.
+    pub vf_reset: bool,
+    // Fx55 and Fx65 increment I.
+    pub memory: bool,
+    // Dxyn waits for the vertical blank interrupt.
+    pub display_wait: bool,
+    // Sprites are clipped at the screen edges instead of wrapping around.
+    pub clipping: bool,
+    // 8xy6 and 8xyE shift Vx in place, ignoring Vy.
+    pub shifting: bool,
+    // Bnnn jumps to nnn + Vx, where x is the highest nibble of nnn.
+    pub jumping: bool,
+}
+
+impl Quirks {
+    pub const PRESETS: [(&'static str, Quirks); 4] = [
+        ("default", Quirks::DEFAULT),
+        ("chip8", Quirks::CHIP8),
+        ("schip", Quirks::SCHIP),
+        ("xochip", Quirks::XOCHIP),
+    ];
+
+    // The original COSMAC VIP interpreter.
+    pub const CHIP8: Quirks = Quirks {
+        vf_reset: true,
+        memory: true,
+        display_wait: true,
+        clipping: true,
+        shifting: false,
+        jumping: false,
+    };
+
+    // SUPER-CHIP 1.1 in low resolution mode.
+    pub const SCHIP: Quirks = Quirks {
+        vf_reset: false,
+        memory: false,
+        display_wait: false,
+        clipping: true,
+        shifting: true,
+        jumping: true,
+    };
+
+    pub const XOCHIP: Quirks = Quirks {
+        vf_reset: false,
+        memory: true,
+        display_wait: false,
+        clipping: false,
+        shifting: false,
+        jumping: false,
+    };
+
+    // What lrchip8 always did, compatible with most games.
+    pub const DEFAULT: Quirks = Quirks {
+        vf_reset: false,
+        memory: false,
+        display_wait: true,
+        clipping: true,
+        shifting: true,
+        jumping: false,
+    };
+
+    // Parses a preset name followed by optional flag changes, e.g.
+    // "chip8" or "schip,-clipping,+display_wait".
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut parts = spec.split(',').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+
+        let mut quirks = Quirks::PRESETS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, quirks)| quirks)
+            .ok_or(format!("Unknown quirks preset: {}", name))?;
+
+        for part in parts {
+            let (on, flag) = match part.split_at(part.len().min(1)) {
+                ("+", flag) => (true, flag),
+                ("-", flag) => (false, flag),
+                _ => return Err(format!("Expected +flag or -flag: {}", part)),
+            };
+
+            quirks.set(flag, on)?;
+        }
+
+        Ok(quirks)
+    }
+
+    pub fn set(&mut self, flag: &str, on: bool) -> Result<(), String> {
+        let flag = match flag {
+            "vf_reset" => &mut self.vf_reset,
+            "memory" => &mut self.memory,
+            "display_wait" => &mut self.display_wait,
+            "clipping" => &mut self.clipping,
+            "shifting" => &mut self.shifting,
+            "jumping" => &mut self.jumping,
+            _ => return Err(format!("Unknown quirk: {}", flag)),
+        };
+
+        *flag = on;
+
+        Ok(())
+    }
+
+    // Packs the flags into a byte, in the order they are declared.
+    pub fn to_bits(self) -> u8 {
+        [
+            self.vf_reset,
+            self.memory,
+            self.display_wait,
+            self.clipping,
+            self.shifting,
+            self.jumping,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &flag)| bits | (flag as u8) << i)
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        let flag = |i: u8| bits & (1 << i) != 0;
+
+        Quirks {
+            vf_reset: flag(0),
+            memory: flag(1),
+            display_wait: flag(2),
+            clipping: flag(3),
+            shifting: flag(4),
+            jumping: flag(5),
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::DEFAULT
+    }
+}
+
+// Why the machine stopped. Faults halt it, with PC left on the instruction
+// that caused them, until it is reset or a state is loaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    UnknownOpcode(u16),
+    // CALL with all the stack levels in use.
+    StackOverflow,
+    // RET outside of a subroutine.
+    StackUnderflow,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::UnknownOpcode(opcode) => write!(f, "Unknown opcode: {:04X}", opcode),
+            Fault::StackOverflow => write!(f, "Stack overflow"),
+            Fault::StackUnderflow => write!(f, "Stack underflow"),
+        }
+    }
+}
+
+// What a tick did. Waiting instructions run again on the next tick.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    Executed,
+    // Dxyn, with the display_wait quirk, until the next frame.
+    WaitingForVBlank,
+    // Fx0A, until a key is pressed and released.
+    WaitingForKey,
+    // Nothing ran: the machine was halted already.
+    Halted,
+    // The instruction faulted and halted the machine.
+    Error(Fault),
+}
+
+// What Chip8::run_frame did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameStats {
+    // Instructions that ran, not counting waits.
+    pub executed: u32,
+    // Instructions of the frame left unrun because the frame ended early.
+    pub skipped: u32,
+    // Of the last tick.
+    pub outcome: StepOutcome,
+}
+
+// What the rom does, as it runs, for observers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    // An instruction ran, or ran again while waiting for the vertical blank
+    // or a key.
+    Instruction {
+        addr: u16,
+        opcode: u16,
+    },
+    MemoryWrite {
+        addr: u16,
+        value: u8,
+    },
+    // At the wrapped position. Collision is VF: whether pixels were erased.
+    SpriteDrawn {
+        x: u8,
+        y: u8,
+        height: u8,
+        collision: bool,
+    },
+    ScreenCleared,
+    SoundStarted,
+    SoundStopped,
+    // Fx0A started waiting for a key.
+    KeyWaitStarted,
+    // The key was pressed and released.
+    KeyWaitEnded(u8),
+    // E.g. on an unknown opcode.
+    Halted(Fault),
+}
+
+// Gets events right after they happen, with the machine as it is then.
+// Closures taking (&Chip8, Event) are observers too.
+pub trait Observer: Send {
+    fn notify(&mut self, chip8: &Chip8, event: Event);
+}
+
+impl<F: FnMut(&Chip8, Event) + Send> Observer for F {
+    fn notify(&mut self, chip8: &Chip8, event: Event) {
+        self(chip8, event);
+    }
+}
+
+// CHIP-8 computers whose roms expect a different machine. They use the
+// original interpreter's quirks. Variants are only chosen through
+// Chip8Builder; the emulator itself always runs a COSMAC VIP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Variant {
+    // COSMAC VIP.
+    Chip8,
+    // COSMAC VIP with the two-page display of hires roms.
+    Chip8Hires,
+    // ETI-660, which loads programs at 0x600 and has a taller display.
+    Eti660,
+}
+
+impl Variant {
+    pub const ALL: [(&'static str, Variant); 3] = [
+        ("chip8", Variant::Chip8),
+        ("chip8-hires", Variant::Chip8Hires),
+        ("eti660", Variant::Eti660),
+    ];
+
+    pub fn parse(name: &str) -> Result<Self, String> {
+        Variant::ALL
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|&(_, variant)| variant)
+            .ok_or(format!("Unknown variant: {}", name))
+    }
+
+    fn platform(self) -> Platform {
+        let default = Platform::default();
+
+        match self {
+            Variant::Chip8 => default,
+            Variant::Chip8Hires => Platform {
+                video_rows: 64,
+                ..default
+            },
+            Variant::Eti660 => Platform {
+                rom_start: 0x600,
+                video_rows: 48,
+                ..default
+            },
+        }
+    }
+}
+
+// The hardware a machine is built with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Platform {
+    mem_size: usize,
+    rom_start: usize,
+    stack_size: usize,
+    video_cols: usize,
+    video_rows: usize,
+    font: Vec<u8>,
+}
+
+impl Default for Platform {
+    fn default() -> Self {
+        Platform {
+            mem_size: MEM_SIZE,
+            rom_start: ROM_START_ADDR,
+            stack_size: STACK_SIZE,
+            video_cols: VIDEO_COLS,
+            video_rows: VIDEO_ROWS,
+            font: FONT_SET.to_vec(),
+        }
+    }
+}
+
+// Configures a machine other than the one Chip8::init makes, e.g.
+// Chip8Builder::init().variant(Variant::Eti660).rng_seed(seed).build().
+#[derive(Clone, Debug, Default)]
+pub struct Chip8Builder {
+    platform: Platform,
+    quirks: Quirks,
+    rng_seed: u64,
+}
+
+impl Chip8Builder {
+    pub fn init() -> Self {
+        Self::default()
+    }
+
+    // Sets the hardware and quirks of a variant, which the other settings
+    // can then change.
+    pub fn variant(mut self, variant: Variant) -> Self {
+        self.platform = variant.platform();
+        self.quirks = Quirks::CHIP8;
+        self
+    }
+
+    // 16 characters of 5 bytes, loaded at address 0.
+    pub fn font(mut self, font: &[u8]) -> Self {
+        self.platform.font = font.to_vec();
+        self
+    }
+
+    // A power of two from 4 KiB to 64 KiB.
+    pub fn mem_size(mut self, mem_size: usize) -> Self {
+        self.platform.mem_size = mem_size;
+        self
+    }
+
+    pub fn rom_start(mut self, rom_start: usize) -> Self {
+        self.platform.rom_start = rom_start;
+        self
+    }
+
+    // How many subroutine calls can be nested.
+    pub fn stack_size(mut self, stack_size: usize) -> Self {
+        self.platform.stack_size = stack_size;
+        self
+    }
+
+    // Up to 256 by 256 pixels, as far as sprite coordinates reach.
+    pub fn video_size(mut self, cols: usize, rows: usize) -> Self {
+        self.platform.video_cols = cols;
+        self.platform.video_rows = rows;
+        self
+    }
+
+    pub fn rng_seed(mut self, rng_seed: u64) -> Self {
+        self.rng_seed = rng_seed;
+        self
+    }
+
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    pub fn build(self) -> Result<Chip8, String> {
+        let platform = self.platform;
+
+        let mem_sizes = 0x1000..=0x10000;
+
+        if !platform.mem_size.is_power_of_two() || !mem_sizes.contains(&platform.mem_size) {
+            return Err(format!("Invalid memory size: {}", platform.mem_size));
+        }
+
+        if platform.font.len() != FONT_SET.len() {
+            return Err(format!("Expected {} bytes of font.", FONT_SET.len()));
+        }
+
+        if platform.rom_start < platform.font.len() || platform.rom_start >= platform.mem_size - 1 {
+            return Err(format!(
+                "The rom can't start at 0x{:X}, over the font or past the end of memory.",
+                platform.rom_start
+            ));
+        }
+
+        if !(1..=u8::MAX as usize).contains(&platform.stack_size) {
+            return Err(format!("Invalid stack size: {}", platform.stack_size));
+        }
+
+        if !(1..=256).contains(&platform.video_cols) || !(1..=256).contains(&platform.video_rows) {
+            return Err(format!(
+                "Invalid display size: {}x{}",
+                platform.video_cols, platform.video_rows
+            ));
+        }
+
+        let mut chip8 = Chip8::with_platform(platform, self.rng_seed);
+        chip8.quirks = self.quirks;
+
+        Ok(chip8)
+    }
+}
+
+// The machine state as plain data, from Chip8::state and for
+// Chip8::set_state.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chip8State {
+    pub v: [u8; NUM_REGS],
+    pub i: u16,
+    pub pc: u16,
+    // The return addresses in use, the last one on top.
+    pub stack: Vec<u16>,
+    pub mem: Vec<u8>,
+    pub video: Vec<u8>,
+    pub keys: [bool; KEYS_SIZE],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub wait_for_key: Option<u8>,
+    pub fault: Option<Fault>,
+}
+
+#[derive(Clone)]
 pub struct Chip8 {
     v: [u8; NUM_REGS],
     i: u16,
     pc: u16,
     sp: u8,
-    stack: [u16; STACK_SIZE],
-    mem: [u8; MEM_SIZE],
-    video: [u8; VIDEO_SIZE],
+    stack: Vec<u16>,
+    mem: Vec<u8>,
+    video: Vec<u8>,
     keys: [bool; KEYS_SIZE],
     delay_t: u8,
     audio_t: u8,
     wait_for_key: Option<u8>,
     vblank: bool,
+    quirks: Quirks,
     rng: Rand32,
+    rng_seed: u64,
+    rom: Vec<u8>,
+    fault: Option<Fault>,
+    symbols: Arc<Symbols>,
+    key_wait: bool,
+    observers: Vec<Arc<Mutex<dyn Observer>>>,
+    platform: Platform,
 }
 
 impl Chip8 {
     pub fn init(rng_seed: u64) -> Self {
-        let mut mem = [0u8; MEM_SIZE];
+        Self::with_platform(Platform::default(), rng_seed)
+    }
+
+    fn with_platform(platform: Platform, rng_seed: u64) -> Self {
+        let mut mem = vec![0u8; platform.mem_size];
 
-        let font_area = &mut mem[..FONT_SET.len()];
-        font_area.copy_from_slice(&FONT_SET);
+        let font_area = &mut mem[..platform.font.len()];
+        font_area.copy_from_slice(&platform.font);
 
         Self {
             v: [0u8; NUM_REGS],
             i: 0,
             pc: 0,
             sp: 0,
-            stack: [0u16; STACK_SIZE],
+            stack: vec![0u16; platform.stack_size],
             mem,
-            video: [0u8; VIDEO_SIZE],
+            video: vec![0u8; platform.video_cols * platform.video_rows],
             keys: [false; KEYS_SIZE],
             delay_t: 0,
             audio_t: 0,
             wait_for_key: None,
             vblank: false,
+            quirks: Quirks::default(),
             rng: Rand32::new(rng_seed),
+            rng_seed,
+            rom: Vec::new(),
+            fault: None,
+            symbols: Arc::default(),
+            key_wait: false,
+            observers: Vec::new(),
+            platform,
         }
     }
 
     pub fn video_cols(&self) -> usize {
-        VIDEO_COLS
+        self.platform.video_cols
     }
 
     pub fn video_rows(&self) -> usize {
-        VIDEO_ROWS
+        self.platform.video_rows
+    }
+
+    // Where roms are loaded and start running.
+    pub fn rom_start(&self) -> usize {
+        self.platform.rom_start
+    }
+
+    pub fn max_rom_size(&self) -> usize {
+        self.platform.mem_size - self.platform.rom_start
     }
 
     pub fn video(&self) -> &[u8] {
@@ -94,65 +553,553 @@ impl Chip8 {
         self.audio_t > 0
     }
 
+    pub fn regs(&self) -> &[u8] {
+        &self.v
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    // The return addresses in use.
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn mem(&self) -> &[u8] {
+        &self.mem
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_t
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.audio_t
+    }
+
+    pub fn set_reg(&mut self, x: usize, value: u8) -> Result<(), String> {
+        let reg = self
+            .v
+            .get_mut(x)
+            .ok_or(format!("Invalid register: {}", x))?;
+
+        *reg = value;
+        Ok(())
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    // The instruction at the last byte of memory can't be fetched.
+    pub fn set_pc(&mut self, pc: u16) -> Result<(), String> {
+        self.check_addr(pc)?;
+
+        self.pc = pc;
+        Ok(())
+    }
+
+    // Replaces the return addresses in use, the last one on top.
+    pub fn set_stack(&mut self, stack: &[u16]) -> Result<(), String> {
+        if stack.len() > self.stack.len() {
+            return Err(format!(
+                "The stack holds up to {} addresses.",
+                self.stack.len()
+            ));
+        }
+
+        for &addr in stack {
+            self.check_addr(addr)?;
+        }
+
+        self.stack[..stack.len()].copy_from_slice(stack);
+        self.stack[stack.len()..].fill(0);
+        self.sp = stack.len() as u8;
+
+        Ok(())
+    }
+
+    pub fn read_mem(&self, addr: usize, len: usize) -> Result<&[u8], String> {
+        let end = addr.saturating_add(len);
+
+        self.mem
+            .get(addr..end)
+            .ok_or("Address out of memory.".to_string())
+    }
+
+    pub fn write_mem(&mut self, addr: usize, data: &[u8]) -> Result<(), String> {
+        let end = addr.saturating_add(data.len());
+
+        self.mem
+            .get_mut(addr..end)
+            .ok_or("Address out of memory.".to_string())?
+            .copy_from_slice(data);
+
+        Ok(())
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_t = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.set_audio_t(value);
+    }
+
+    pub fn keys(&self) -> &[bool] {
+        &self.keys
+    }
+
+    // The key pressed during Fx0A, whose release it is waiting for.
+    pub fn wait_for_key(&self) -> Option<u8> {
+        self.wait_for_key
+    }
+
+    // A copy of the machine state, to inspect or change in bulk. The rom,
+    // quirks and random generator are not part of it.
+    pub fn state(&self) -> Chip8State {
+        Chip8State {
+            v: self.v,
+            i: self.i,
+            pc: self.pc,
+            stack: self.stack().to_vec(),
+            mem: self.mem.to_vec(),
+            video: self.video.to_vec(),
+            keys: self.keys,
+            delay_timer: self.delay_t,
+            sound_timer: self.audio_t,
+            wait_for_key: self.wait_for_key,
+            fault: self.fault,
+        }
+    }
+
+    // Leaves the current state as it is if the new one is not valid.
+    pub fn set_state(&mut self, state: &Chip8State) -> Result<(), String> {
+        if state.mem.len() != self.mem.len() {
+            return Err(format!("Expected {} bytes of memory.", self.mem.len()));
+        }
+
+        if state.video.len() != self.video.len() {
+            return Err(format!("Expected {} bytes of video.", self.video.len()));
+        }
+
+        if let Some(key) = state.wait_for_key.filter(|&key| key as usize >= KEYS_SIZE) {
+            return Err(format!("Invalid key: {}", key));
+        }
+
+        self.check_addr(state.pc)?;
+        self.set_stack(&state.stack)?;
+
+        self.v = state.v;
+        self.i = state.i;
+        self.pc = state.pc;
+        self.mem.copy_from_slice(&state.mem);
+        self.video.copy_from_slice(&state.video);
+        self.keys = state.keys;
+        self.delay_t = state.delay_timer;
+        self.set_audio_t(state.sound_timer);
+        self.wait_for_key = state.wait_for_key;
+        self.fault = state.fault;
+        // A key wait the new state is in starts over for observers, as it
+        // does after load_state.
+        self.key_wait = false;
+
+        Ok(())
+    }
+
     pub fn write_keys(&mut self, keys: &[bool]) {
         self.keys.copy_from_slice(&keys[..KEYS_SIZE]);
     }
 
     pub fn update_timers(&mut self) {
         self.delay_t = self.delay_t.saturating_sub(1);
-        self.audio_t = self.audio_t.saturating_sub(1);
+        self.set_audio_t(self.audio_t.saturating_sub(1));
+    }
+
+    pub fn load(&mut self, rom: &[u8]) -> Result<(), String> {
+        if rom.len() > self.max_rom_size() {
+            return Err("The rom is too big to fit in memory.".to_string());
+        }
+
+        self.load_rom(rom);
+
+        Ok(())
     }
 
-    pub fn load(&mut self, rom: &[u8]) {
+    // A rom that fits in memory.
+    fn load_rom(&mut self, rom: &[u8]) {
+        let start = self.platform.rom_start;
+
         println!("Loading rom ({} bytes)...", rom.len());
 
-        if let Some(rom_area) = self
-            .mem
-            .get_mut(ROM_START_ADDR..(ROM_START_ADDR + rom.len()))
-        {
-            rom_area.copy_from_slice(rom);
-            println!("{} bytes loaded.", rom.len());
-        } else {
-            panic!("The rom is too big to fit in memory.");
+        self.mem[start..(start + rom.len())].copy_from_slice(rom);
+        println!("{} bytes loaded.", rom.len());
+
+        self.pc = start as u16;
+        self.rom = rom.to_vec();
+    }
+
+    // Writes a new version of the rom over the loaded one, keeping the rest
+    // of the state, so a program being developed can keep running.
+    pub fn patch(&mut self, rom: &[u8]) -> Result<(), String> {
+        let start = self.platform.rom_start;
+
+        if rom.len() > self.max_rom_size() {
+            return Err("The rom is too big to fit in memory.".to_string());
         }
 
-        self.pc = ROM_START_ADDR as u16;
+        self.mem[start..(start + self.rom.len())].fill(0);
+        self.mem[start..(start + rom.len())].copy_from_slice(rom);
+        self.rom = rom.to_vec();
+
+        Ok(())
     }
 
-    pub fn load16(&mut self, rom16: &[u16]) {
+    // A hash of the whole machine state, to check that two instances are in
+    // sync. FNV-1a, so it is the same on every platform and build.
+    pub fn state_hash(&self) -> u64 {
+        let (rng_state, rng_inc) = self.rng.state();
+        let words = [
+            self.i,
+            self.pc,
+            self.sp as u16,
+            self.delay_t as u16,
+            self.audio_t as u16,
+        ];
+
+        self.v
+            .iter()
+            .chain(&self.mem)
+            .chain(&self.video)
+            .copied()
+            .chain(
+                self.stack
+                    .iter()
+                    .chain(&words)
+                    .flat_map(|w| w.to_le_bytes()),
+            )
+            .chain(rng_state.to_le_bytes())
+            .chain(rng_inc.to_le_bytes())
+            .fold(0xCBF29CE484222325, |hash, byte| {
+                (hash ^ byte as u64).wrapping_mul(0x100000001B3)
+            })
+    }
+
+    // The whole machine state, including the loaded rom, as bytes.
+    pub fn save_state(&self) -> Vec<u8> {
+        let (rng_state, rng_inc) = self.rng.state();
+        let mut state = STATE_MAGIC.to_vec();
+
+        state.extend(self.v);
+        state.extend(self.i.to_le_bytes());
+        state.extend(self.pc.to_le_bytes());
+        state.push(self.sp);
+        state.extend(self.stack.iter().flat_map(|addr| addr.to_le_bytes()));
+        state.extend(&self.mem);
+        state.extend(&self.video);
+        state.extend(self.keys.map(|key| key as u8));
+        state.push(self.delay_t);
+        state.push(self.audio_t);
+        state.push(self.wait_for_key.unwrap_or(0xFF));
+        state.push(self.vblank as u8);
+        state.push(self.quirks.to_bits());
+        state.extend(rng_state.to_le_bytes());
+        state.extend(rng_inc.to_le_bytes());
+        state.extend(self.rng_seed.to_le_bytes());
+        state.extend((self.rom.len() as u32).to_le_bytes());
+        state.extend(&self.rom);
+
+        state
+    }
+
+    // Restores a state from save_state, leaving the current one as it is if
+    // the state is not valid. It must come from a machine built the same way.
+    // Symbols and observers are kept.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader(state);
+
+        if reader.bytes(STATE_MAGIC.len())? != STATE_MAGIC {
+            return Err("Not a lrchip8 state.".to_string());
+        }
+
+        let mut chip8 = Chip8::with_platform(self.platform.clone(), 0);
+
+        chip8.v.copy_from_slice(reader.bytes(NUM_REGS)?);
+        chip8.i = reader.u16()?;
+        chip8.pc = reader.u16()?;
+        chip8.sp = reader.u8()?;
+        for addr in chip8.stack.iter_mut() {
+            *addr = reader.u16()?;
+        }
+        chip8.mem = reader.bytes(chip8.mem.len())?.to_vec();
+        chip8.video = reader.bytes(chip8.video.len())?.to_vec();
+        for key in chip8.keys.iter_mut() {
+            *key = reader.u8()? != 0;
+        }
+        chip8.delay_t = reader.u8()?;
+        chip8.audio_t = reader.u8()?;
+        chip8.wait_for_key = Some(reader.u8()?).filter(|&key| key != 0xFF);
+        chip8.vblank = reader.u8()? != 0;
+        chip8.quirks = Quirks::from_bits(reader.u8()?);
+        chip8.rng = Rand32::from_state((reader.u64()?, reader.u64()?));
+        chip8.rng_seed = reader.u64()?;
+        let rom_len = reader.u32()? as usize;
+        chip8.rom = reader.bytes(rom_len)?.to_vec();
+
+        let valid = chip8.sp as usize <= chip8.stack.len()
+            && chip8.check_addr(chip8.pc).is_ok()
+            && chip8.stack[..chip8.sp as usize]
+                .iter()
+                .all(|&addr| chip8.check_addr(addr).is_ok())
+            && chip8
+                .wait_for_key
+                .is_none_or(|key| (key as usize) < KEYS_SIZE)
+            && rom_len <= chip8.max_rom_size()
+            && reader.0.is_empty();
+
+        if !valid {
+            return Err("Invalid lrchip8 state.".to_string());
+        }
+
+        chip8.symbols = self.symbols.clone();
+        chip8.observers = self.observers.clone();
+        *self = chip8;
+
+        Ok(())
+    }
+
+    // Restarts the loaded rom with the same seed and quirks.
+    pub fn reset(&mut self) {
+        let mut chip8 = Chip8::with_platform(self.platform.clone(), self.rng_seed);
+        chip8.quirks = self.quirks;
+        chip8.symbols = self.symbols.clone();
+        chip8.observers = self.observers.clone();
+        chip8.load_rom(&self.rom);
+
+        *self = chip8;
+    }
+
+    pub fn load16(&mut self, rom16: &[u16]) -> Result<(), String> {
         let rom8 = rom16
             .iter()
             .flat_map(|&w| [(w >> 8) as u8, (w & 0x00FF) as u8].into_iter())
             .collect::<Vec<u8>>();
 
-        self.load(&rom8);
+        self.load(&rom8)
     }
 
     pub fn set_vblank(&mut self, vblank: bool) {
         self.vblank = vblank;
     }
 
-    pub fn tick(&mut self) {
+    pub fn rng_seed(&self) -> u64 {
+        self.rng_seed
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    pub fn fault(&self) -> Option<Fault> {
+        self.fault
+    }
+
+    // Names for the rom's addresses in messages. They are kept on reset.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = Arc::new(symbols);
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    // Observers are kept on reset and shared by clones. The handle gives
+    // access to the observer afterwards, e.g. to read what it collected.
+    pub fn add_observer<O: Observer + 'static>(&mut self, observer: O) -> Arc<Mutex<O>> {
+        let observer = Arc::new(Mutex::new(observer));
+        self.observers.push(observer.clone());
+
+        observer
+    }
+
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    // A 60th of a second, as the emulator runs it: the timers count down, the
+    // vertical blank happens, then the instructions of a frame at ips run.
+    // The frame ends early once the rom waits for the next one or halts,
+    // which the rest of it couldn't change. Key waits go on, as keys can
+    // change between ticks.
+    pub fn run_frame(&mut self, ips: u32) -> FrameStats {
+        self.run_frame_with(ips, |chip8, _| Some(chip8.tick()))
+    }
+
+    // run_frame, with each tick made by `tick`, given the machine and the
+    // instruction's index in the frame, e.g. to write the keys or call hooks
+    // around it. Returning None ends the frame there.
+    pub fn run_frame_with<F>(&mut self, ips: u32, mut tick: F) -> FrameStats
+    where
+        F: FnMut(&mut Chip8, u32) -> Option<StepOutcome>,
+    {
+        let iters = iters_per_frame(ips);
+        let mut stats = FrameStats {
+            executed: 0,
+            skipped: 0,
+            outcome: StepOutcome::Executed,
+        };
+
+        self.update_timers();
+
+        for i in 0..iters {
+            self.set_vblank(i == 0);
+
+            let Some(outcome) = tick(self, i) else {
+                stats.skipped = iters - i;
+                break;
+            };
+            stats.outcome = outcome;
+
+            match outcome {
+                StepOutcome::Executed => stats.executed += 1,
+                StepOutcome::WaitingForKey => {}
+                _ => {
+                    stats.skipped = iters - i - 1;
+                    break;
+                }
+            }
+        }
+
+        stats
+    }
+
+    pub fn tick(&mut self) -> StepOutcome {
+        if self.fault.is_some() {
+            return StepOutcome::Halted;
+        }
+
+        let addr = self.pc;
         let opcode = self.fetch();
 
         #[cfg(debug_assertions)]
         println!(
-            "[OP=0x{:0>4X}] [I=0x{:0>4X}, PC=0x{:0>4X}, SP=0x{:0>4X}, V={:?}]",
-            opcode, self.i, self.pc, self.sp, self.v
+            "[OP=0x{:0>4X}] [I=0x{:0>4X}, PC=0x{:0>4X}{}, SP=0x{:0>4X}, V={:?}]",
+            opcode,
+            self.i,
+            self.pc,
+            self.pc_symbol(),
+            self.sp,
+            self.v
         );
 
         self.execute(opcode);
+
+        if let Some(fault) = self.fault {
+            return StepOutcome::Error(fault);
+        }
+
+        self.notify(Event::Instruction { addr, opcode });
+
+        // Waits leave PC on the instruction, and so do jumps to themselves.
+        if self.pc != addr {
+            StepOutcome::Executed
+        } else if opcode & 0xF0FF == 0xF00A {
+            StepOutcome::WaitingForKey
+        } else if opcode & 0xF000 == 0xD000 {
+            StepOutcome::WaitingForVBlank
+        } else {
+            StepOutcome::Executed
+        }
     }
 
     fn fetch(&mut self) -> u16 {
         let pc = self.pc as usize;
-        let opcode = (self.mem[pc] as u16) << 8 | self.mem[pc + 1] as u16;
+        let opcode = (self.mem[pc] as u16) << 8 | self.mem[(pc + 1) % self.mem.len()] as u16;
 
-        self.pc += 2;
+        self.skip();
 
         opcode
     }
 
+    fn skip(&mut self) {
+        self.pc = self.pc.wrapping_add(2) & self.addr_mask();
+    }
+
+    // Runs the current instruction again on the next tick.
+    fn retry(&mut self) {
+        self.pc = self.pc.wrapping_sub(2) & self.addr_mask();
+    }
+
+    // Addresses wrap around at the end of memory.
+    fn addr_mask(&self) -> u16 {
+        (self.mem.len() - 1) as u16
+    }
+
+    // Whether an instruction can be fetched from an address.
+    fn check_addr(&self, addr: u16) -> Result<(), String> {
+        if addr as usize >= self.mem.len() - 1 {
+            return Err(format!("Address out of range: 0x{:X}", addr));
+        }
+
+        Ok(())
+    }
+
+    fn halt(&mut self, fault: Fault) {
+        self.retry();
+        self.fault = Some(fault);
+        self.notify(Event::Halted(fault));
+    }
+
+    fn notify(&self, event: Event) {
+        for observer in &self.observers {
+            observer.lock().unwrap().notify(self, event);
+        }
+    }
+
+    fn write(&mut self, addr: usize, value: u8) {
+        self.mem[addr] = value;
+        self.notify(Event::MemoryWrite {
+            addr: addr as u16,
+            value,
+        });
+    }
+
+    fn set_audio_t(&mut self, audio_t: u8) {
+        let audio = self.audio();
+        self.audio_t = audio_t;
+
+        match (audio, self.audio()) {
+            (false, true) => self.notify(Event::SoundStarted),
+            (true, false) => self.notify(Event::SoundStopped),
+            _ => {}
+        }
+    }
+
+    // Memory address I + offset.
+    fn addr(&self, offset: usize) -> usize {
+        (self.i as usize + offset) % self.mem.len()
+    }
+
+    // " (draw_paddle+0x4)" after the PC in messages, with symbols loaded.
+    fn pc_symbol(&self) -> String {
+        self.symbols
+            .locate(self.pc)
+            .map_or(String::new(), |name| format!(" ({})", name))
+    }
+
     fn execute(&mut self, opcode: u16) {
         match opcode & 0xF000 {
             0x0000 => match opcode {
@@ -164,7 +1111,7 @@ impl Chip8 {
             0x2000 => self.op_2nnn(dec_addr!(opcode)),
             0x3000 => self.op_3xkk(dec_x!(opcode), dec_byte!(opcode)),
             0x4000 => self.op_4xkk(dec_x!(opcode), dec_byte!(opcode)),
-            0x5000 => self.op_5xy0(dec_x!(opcode), dec_y!(opcode)),
+            0x5000 if opcode & 0x000F == 0 => self.op_5xy0(dec_x!(opcode), dec_y!(opcode)),
             0x6000 => self.op_6xkk(dec_x!(opcode), dec_byte!(opcode)),
             0x7000 => self.op_7xkk(dec_x!(opcode), dec_byte!(opcode)),
             0x8000 => match opcode & 0x000F {
@@ -174,20 +1121,20 @@ impl Chip8 {
                 0x0003 => self.op_8xy3(dec_x!(opcode), dec_y!(opcode)),
                 0x0004 => self.op_8xy4(dec_x!(opcode), dec_y!(opcode)),
                 0x0005 => self.op_8xy5(dec_x!(opcode), dec_y!(opcode)),
-                0x0006 => self.op_8xy6(dec_x!(opcode)),
+                0x0006 => self.op_8xy6(dec_x!(opcode), dec_y!(opcode)),
                 0x0007 => self.op_8xy7(dec_x!(opcode), dec_y!(opcode)),
-                0x000E => self.op_8xye(dec_x!(opcode)),
-                _ => dec_error!(opcode),
+                0x000E => self.op_8xye(dec_x!(opcode), dec_y!(opcode)),
+                _ => self.halt(dec_error!(opcode)),
             },
-            0x9000 => self.op_9xy0(dec_x!(opcode), dec_y!(opcode)),
+            0x9000 if opcode & 0x000F == 0 => self.op_9xy0(dec_x!(opcode), dec_y!(opcode)),
             0xA000 => self.op_annn(dec_addr!(opcode)),
-            0xB000 => self.op_bnnn(dec_addr!(opcode)),
+            0xB000 => self.op_bnnn(dec_x!(opcode), dec_addr!(opcode)),
             0xC000 => self.op_cxkk(dec_x!(opcode), dec_byte!(opcode)),
             0xD000 => self.op_dxyn(dec_x!(opcode), dec_y!(opcode), dec_nibble!(opcode)),
             0xE000 => match opcode & 0x00FF {
                 0x009E => self.op_ex9e(dec_x!(opcode)),
                 0x00A1 => self.op_exa1(dec_x!(opcode)),
-                _ => dec_error!(opcode),
+                _ => self.halt(dec_error!(opcode)),
             },
             0xF000 => match opcode & 0x00FF {
                 0x0007 => self.op_fx07(dec_x!(opcode)),
@@ -199,9 +1146,9 @@ impl Chip8 {
                 0x0033 => self.op_fx33(dec_x!(opcode)),
                 0x0055 => self.op_fx55(dec_x!(opcode)),
                 0x0065 => self.op_fx65(dec_x!(opcode)),
-                _ => dec_error!(opcode),
+                _ => self.halt(dec_error!(opcode)),
             },
-            _ => dec_error!(opcode),
+            _ => self.halt(dec_error!(opcode)),
         }
     }
 
@@ -209,13 +1156,18 @@ impl Chip8 {
     // Clear the display.
     fn op_00e0(&mut self) {
         self.video.fill(0);
+        self.notify(Event::ScreenCleared);
     }
 
     // 00EE - RET
     // Return from a subroutine.
     fn op_00ee(&mut self) {
+        if self.sp == 0 {
+            return self.halt(Fault::StackUnderflow);
+        }
+
         self.sp -= 1;
-        self.pc = self.stack[self.sp as usize];
+        self.pc = self.stack[self.sp as usize] & self.addr_mask();
     }
 
     // 0nnn - SYS addr
@@ -224,8 +1176,13 @@ impl Chip8 {
     // It is ignored by modern interpreters.
     fn op_0nnn(&self, opcode: u16) {
         println!(
-            "Machine code routine: [OP=0x{:0>4X}] [I=0x{:0>4X}, PC=0x{:0>4X}, SP=0x{:0>4X}, V={:?}]",
-            opcode, self.i, self.pc, self.sp, self.v
+            "Machine code routine: [OP=0x{:0>4X}] [I=0x{:0>4X}, PC=0x{:0>4X}{}, SP=0x{:0>4X}, V={:?}]",
+            opcode,
+            self.i,
+            self.pc,
+            self.pc_symbol(),
+            self.sp,
+            self.v
         );
     }
 
@@ -238,6 +1195,10 @@ impl Chip8 {
     // 2nnn - CALL addr
     // Call subroutine at nnn.
     fn op_2nnn(&mut self, addr: u16) {
+        if self.sp as usize == self.stack.len() {
+            return self.halt(Fault::StackOverflow);
+        }
+
         self.stack[self.sp as usize] = self.pc;
         self.sp += 1;
         self.pc = addr;
@@ -247,7 +1208,7 @@ impl Chip8 {
     // Skip next instruction if Vx = kk.
     fn op_3xkk(&mut self, x: usize, kk: u8) {
         if self.v[x] == kk {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -255,7 +1216,7 @@ impl Chip8 {
     // Skip next instruction if Vx != kk.
     fn op_4xkk(&mut self, x: usize, kk: u8) {
         if self.v[x] != kk {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -263,7 +1224,7 @@ impl Chip8 {
     // Skip next instruction if Vx = Vy.
     fn op_5xy0(&mut self, x: usize, y: usize) {
         if self.v[x] == self.v[y] {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -289,18 +1250,30 @@ impl Chip8 {
     // Set Vx = Vx OR Vy.
     fn op_8xy1(&mut self, x: usize, y: usize) {
         self.v[x] |= self.v[y];
+
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
     }
 
     // 8xy2 - AND Vx, Vy
     // Set Vx = Vx AND Vy.
     fn op_8xy2(&mut self, x: usize, y: usize) {
         self.v[x] &= self.v[y];
+
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
     }
 
     // 8xy3 - XOR Vx, Vy
     // Set Vx = Vx XOR Vy.
     fn op_8xy3(&mut self, x: usize, y: usize) {
         self.v[x] ^= self.v[y];
+
+        if self.quirks.vf_reset {
+            self.v[0xF] = 0;
+        }
     }
 
     // 8xy4 - ADD Vx, Vy
@@ -324,7 +1297,11 @@ impl Chip8 {
     // 8xy6 - SHR Vx {, Vy}
     // Set Vx = Vx SHR 1.
     // If the least-significant bit of Vx is 1, then VF is set to 1, otherwise 0. Then Vx is divided by 2.
-    fn op_8xy6(&mut self, x: usize) {
+    fn op_8xy6(&mut self, x: usize, y: usize) {
+        if !self.quirks.shifting {
+            self.v[x] = self.v[y];
+        }
+
         let vf = self.v[x] & 1;
 
         self.v[x] >>= 1;
@@ -343,7 +1320,11 @@ impl Chip8 {
     // 8xyE - SHL Vx {, Vy}
     // Set Vx = Vx SHL 1.
     // If the most-significant bit of Vx is 1, then VF is set to 1, otherwise to 0. Then Vx is multiplied by 2.
-    fn op_8xye(&mut self, x: usize) {
+    fn op_8xye(&mut self, x: usize, y: usize) {
+        if !self.quirks.shifting {
+            self.v[x] = self.v[y];
+        }
+
         let vf = (self.v[x] >> 7) & 1;
 
         self.v[x] <<= 1;
@@ -354,7 +1335,7 @@ impl Chip8 {
     // Skip next instruction if Vx != Vy.
     fn op_9xy0(&mut self, x: usize, y: usize) {
         if self.v[x] != self.v[y] {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -366,8 +1347,14 @@ impl Chip8 {
 
     // Bnnn - JP V0, addr
     // Jump to location nnn + V0.
-    fn op_bnnn(&mut self, nnn: u16) {
-        self.pc = nnn + self.v[0] as u16;
+    fn op_bnnn(&mut self, x: usize, nnn: u16) {
+        let v = if self.quirks.jumping {
+            self.v[x]
+        } else {
+            self.v[0]
+        };
+
+        self.pc = (nnn + v as u16) & self.addr_mask();
     }
 
     // Cxkk - RND Vx, byte
@@ -380,39 +1367,33 @@ impl Chip8 {
     // Display n-byte sprite starting at memory location I at (Vx, Vy), set VF = collision.
     fn op_dxyn(&mut self, x: usize, y: usize, n: u8) {
         // Wait for vblank.
-        if !self.vblank {
-            self.pc -= 2;
+        if self.quirks.display_wait && !self.vblank {
+            self.retry();
             return;
         }
 
         // Wrap x and y.
-        let x = self.v[x] as usize % VIDEO_COLS;
-        let y = self.v[y] as usize % VIDEO_ROWS;
+        let (cols, rows) = (self.platform.video_cols, self.platform.video_rows);
+        let x = self.v[x] as usize % cols;
+        let y = self.v[y] as usize % rows;
 
-        // Memory area.
-        let addr_start = self.i as usize;
         let n = n as usize;
 
-        // Clip rows.
-        let addr_end = if (y + n) < VIDEO_ROWS {
-            addr_start + n
-        } else {
-            addr_start + (VIDEO_ROWS - y)
-        };
-
-        // Clip cols.
-        let bit_max = if (x + 8) < VIDEO_COLS {
-            8
+        // Clip rows and cols, unless the sprite wraps around.
+        let (height, bit_max) = if self.quirks.clipping {
+            (n.min(rows - y), 8.min(cols - x))
         } else {
-            VIDEO_COLS - x
+            (n, 8)
         };
 
         self.v[0xF] = 0;
 
-        for (y_ofst, addr) in (addr_start..addr_end).enumerate() {
+        for y_ofst in 0..height {
+            let addr = self.addr(y_ofst);
+
             for i in 0..bit_max {
                 if (self.mem[addr] & (0x80 >> i)) != 0 {
-                    let pixel_pos = ((y + y_ofst) * VIDEO_COLS) + x + i;
+                    let pixel_pos = ((y + y_ofst) % rows) * cols + (x + i) % cols;
 
                     if self.video[pixel_pos] == 1 {
                         self.v[0xF] = 1;
@@ -422,25 +1403,33 @@ impl Chip8 {
                 }
             }
         }
+
+        self.notify(Event::SpriteDrawn {
+            x: x as u8,
+            y: y as u8,
+            height: n as u8,
+            collision: self.v[0xF] == 1,
+        });
     }
 
     // Ex9E - SKP Vx
     // Skip next instruction if key with the value of Vx is pressed.
     fn op_ex9e(&mut self, x: usize) {
-        let vx = self.v[x] as usize;
+        // Only the lowest nibble names a key.
+        let vx = self.v[x] as usize & 0xF;
 
         if self.keys[vx] {
-            self.pc += 2;
+            self.skip();
         }
     }
 
     // ExA1 - SKNP Vx
     // Skip next instruction if key with the value of Vx is not pressed.
     fn op_exa1(&mut self, x: usize) {
-        let vx = self.v[x] as usize;
+        let vx = self.v[x] as usize & 0xF;
 
         if !self.keys[vx] {
-            self.pc += 2;
+            self.skip();
         }
     }
 
@@ -456,12 +1445,19 @@ impl Chip8 {
         #[cfg(debug_assertions)]
         println!("Waiting for key...");
 
+        if !self.key_wait {
+            self.key_wait = true;
+            self.notify(Event::KeyWaitStarted);
+        }
+
         if let Some(key) = self.wait_for_key {
             if !self.keys[key as usize] {
                 #[cfg(debug_assertions)]
                 println!("Got key 0x{:X}.", key);
                 self.v[x] = key;
                 self.wait_for_key = None;
+                self.key_wait = false;
+                self.notify(Event::KeyWaitEnded(key));
                 return;
             }
         } else if let Some(key) = self.keys.iter().position(|&k| k) {
@@ -469,7 +1465,7 @@ impl Chip8 {
         }
 
         // Try again.
-        self.pc -= 2;
+        self.retry();
     }
 
     // Fx15 - LD DT, Vx
@@ -481,7 +1477,7 @@ impl Chip8 {
     // Fx18 - LD ST, Vx
     // Set sound timer = Vx.
     fn op_fx18(&mut self, x: usize) {
-        self.audio_t = self.v[x];
+        self.set_audio_t(self.v[x]);
     }
 
     // Fx1E - ADD I, Vx
@@ -505,18 +1501,21 @@ impl Chip8 {
     // Store BCD representation of Vx in memory locations I, I+1, and I+2.
     fn op_fx33(&mut self, x: usize) {
         let n = self.v[x];
-        let i = self.i as usize;
 
-        self.mem[i] = n / 100;
-        self.mem[i + 1] = n % 100 / 10;
-        self.mem[i + 2] = n % 10;
+        self.write(self.addr(0), n / 100);
+        self.write(self.addr(1), n % 100 / 10);
+        self.write(self.addr(2), n % 10);
     }
 
     // Fx55 - LD [I], Vx
     // Store registers V0 through Vx in memory starting at location I.
     fn op_fx55(&mut self, x: usize) {
         for i in 0..=x {
-            self.mem[self.i as usize + i] = self.v[i];
+            self.write(self.addr(i), self.v[i]);
+        }
+
+        if self.quirks.memory {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
     }
 
@@ -524,19 +1523,69 @@ impl Chip8 {
     // Read registers V0 through Vx from memory starting at location I.
     fn op_fx65(&mut self, x: usize) {
         for i in 0..=x {
-            self.v[i] = self.mem[self.i as usize + i];
+            self.v[i] = self.mem[self.addr(i)];
+        }
+
+        if self.quirks.memory {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
     }
 }
 
+// How many instructions run per frame at a speed in instructions per second.
+pub fn iters_per_frame(ips: u32) -> u32 {
+    ((ips as f64 / FPS as f64).round() as u32).max(1)
+}
+
+struct StateReader<'a>(&'a [u8]);
+
+impl<'a> StateReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < len {
+            return Err("Truncated lrchip8 state.".to_string());
+        }
+
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod proptests;
+
 #[cfg(test)]
 mod tests {
-    use super::Chip8;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use super::{
+        Chip8, Chip8Builder, Event, Fault, Observer, Quirks, StepOutcome, Symbols, Variant,
+    };
 
     fn load_chip8(rom16: &[u16]) -> Chip8 {
         let mut chip8 = Chip8::init(0);
 
-        chip8.load16(rom16);
+        chip8.load16(rom16).unwrap();
 
         chip8
     }
@@ -634,6 +1683,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::identity_op)]
     fn test_op_fx29_2() {
         let mut chip8 = load_chip8(&[0x6001, 0xF029]);
 
@@ -684,4 +1734,407 @@ mod tests {
             [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0xA, 0xB, 0xC, 0xD, 0xE, 0xF]
         );
     }
+
+    #[test]
+    fn test_quirks_parse() {
+        assert_eq!(Quirks::parse("chip8"), Ok(Quirks::CHIP8));
+
+        let quirks = Quirks::parse("schip, -clipping, +display_wait").unwrap();
+        assert!(!quirks.clipping);
+        assert!(quirks.display_wait);
+        assert!(quirks.jumping);
+
+        assert!(Quirks::parse("cosmac").is_err());
+        assert!(Quirks::parse("chip8,clipping").is_err());
+    }
+
+    #[test]
+    fn test_quirk_shifting() {
+        let mut chip8 = load_chip8(&[0x6005, 0x6103, 0x8016]);
+        chip8.set_quirks(Quirks::CHIP8);
+
+        for _ in 0..3 {
+            chip8.tick();
+        }
+
+        assert_eq!(chip8.v[0], 1);
+        assert_eq!(chip8.v[0xF], 1);
+    }
+
+    #[test]
+    fn test_quirk_memory() {
+        let mut chip8 = load_chip8(&[0xA300, 0xF255]);
+        chip8.set_quirks(Quirks::CHIP8);
+
+        chip8.tick();
+        chip8.tick();
+
+        assert_eq!(chip8.i, 0x303);
+    }
+
+    #[test]
+    fn test_quirk_clipping() {
+        let mut chip8 = load_chip8(&[0x603E, 0x611F, 0xA000, 0xD012]);
+        chip8.set_quirks(Quirks::XOCHIP);
+
+        for _ in 0..4 {
+            chip8.tick();
+        }
+
+        // The "0" glyph wraps to the other side of the screen.
+        assert_eq!(chip8.video[31 * 64 + 62], 1);
+        assert_eq!(chip8.video[31 * 64], 1);
+        assert_eq!(chip8.video[62], 1);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut chip8 = load_chip8(&[0x6012, 0xC1FF, 0x00E0]);
+        chip8.set_quirks(Quirks::CHIP8);
+
+        chip8.tick();
+        chip8.tick();
+        let random = chip8.v[1];
+        chip8.reset();
+
+        assert_eq!(chip8.pc, 0x200);
+        assert_eq!(chip8.v[0], 0);
+        assert_eq!(chip8.quirks(), Quirks::CHIP8);
+
+        chip8.tick();
+        chip8.tick();
+
+        // Same seed, same numbers.
+        assert_eq!(chip8.v[1], random);
+    }
+
+    #[test]
+    fn test_patch() {
+        let mut chip8 = load_chip8(&[0x6012, 0x6034, 0x1204]);
+
+        chip8.tick();
+        chip8.patch(&[0x60, 0x12, 0x61, 0x56]).unwrap();
+        chip8.tick();
+
+        assert_eq!(chip8.v[0], 0x12);
+        assert_eq!(chip8.v[1], 0x56);
+        assert_eq!(chip8.mem[0x204], 0);
+        assert!(chip8.patch(&[0; 4096]).is_err());
+        assert!(chip8.load(&[0; 4096]).is_err());
+        assert_eq!(chip8.v[1], 0x56);
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let mut chip8 = load_chip8(&[0x6012, 0x6012]);
+        let other = load_chip8(&[0x6012, 0x6012]);
+
+        assert_eq!(chip8.state_hash(), other.state_hash());
+
+        chip8.tick();
+
+        assert_ne!(chip8.state_hash(), other.state_hash());
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut chip8 = load_chip8(&[0x6012, 0x2206, 0x00E0, 0xC1FF, 0xA22A, 0xD015]);
+        chip8.set_quirks(Quirks::XOCHIP);
+
+        for _ in 0..5 {
+            chip8.tick();
+        }
+
+        let state = chip8.save_state();
+        let mut other = Chip8::init(1);
+        other.set_symbols(Symbols::parse("main 0x200").unwrap());
+        other.load_state(&state).unwrap();
+        assert_eq!(other.symbols().format(0x202), "main+0x2");
+
+        assert_eq!(other.state_hash(), chip8.state_hash());
+        assert_eq!(other.quirks(), Quirks::XOCHIP);
+        assert_eq!(other.save_state(), state);
+
+        chip8.tick();
+        other.tick();
+
+        assert_eq!(other.state_hash(), chip8.state_hash());
+        assert!(other.load_state(&state[..100]).is_err());
+        assert!(other.load_state(b"LRC8STA0").is_err());
+
+        // A return address past the end of memory, after the magic, V, I, PC
+        // and SP.
+        let mut invalid = state.clone();
+        invalid[29..31].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        assert!(other.load_state(&invalid).is_err());
+    }
+
+    #[test]
+    fn test_state() {
+        let mut chip8 = load_chip8(&[0x2204, 0x0000, 0x6012, 0x00EE]);
+
+        chip8.tick();
+        chip8.tick();
+        assert_eq!(chip8.stack(), &[0x202]);
+
+        let state = chip8.state();
+        assert_eq!(state.v[0], 0x12);
+        assert_eq!(state.pc, 0x206);
+
+        assert!(chip8.set_reg(16, 0).is_err());
+        assert!(chip8.set_pc(0xFFF).is_err());
+        assert!(chip8.set_stack(&[0x200; 17]).is_err());
+        assert!(chip8.read_mem(0xFFE, 3).is_err());
+        assert!(chip8.write_mem(0x1000, &[0]).is_err());
+
+        chip8.set_reg(0, 0x34).unwrap();
+        chip8.set_stack(&[]).unwrap();
+        chip8.write_mem(0x300, &[1, 2]).unwrap();
+        assert_eq!(chip8.read_mem(0x300, 2), Ok(&[1, 2][..]));
+        assert_eq!(chip8.sp(), 0);
+
+        let mut invalid = state.clone();
+        invalid.mem.pop();
+        assert!(chip8.set_state(&invalid).is_err());
+        assert_eq!(chip8.regs()[0], 0x34);
+
+        chip8.set_state(&state).unwrap();
+        assert_eq!(chip8.state(), state);
+
+        chip8.tick();
+        assert_eq!(chip8.pc(), 0x202);
+    }
+
+    #[test]
+    fn test_builder() {
+        let mut chip8 = Chip8Builder::init()
+            .variant(Variant::Eti660)
+            .stack_size(1)
+            .build()
+            .unwrap();
+        chip8.load16(&[0x2602, 0x2604]).unwrap();
+
+        assert_eq!(chip8.quirks(), Quirks::CHIP8);
+        assert_eq!((chip8.video_cols(), chip8.video_rows()), (64, 48));
+        assert_eq!(chip8.pc(), 0x600);
+        assert_eq!(chip8.max_rom_size(), 0xA00);
+
+        chip8.tick();
+        chip8.tick();
+        assert_eq!(chip8.fault(), Some(Fault::StackOverflow));
+
+        let state = chip8.save_state();
+        assert!(Chip8::init(0).load_state(&state).is_err());
+        chip8.reset();
+        assert_eq!(chip8.pc(), 0x600);
+
+        assert_eq!(Variant::parse("chip8-hires"), Ok(Variant::Chip8Hires));
+        assert!(Variant::parse("dream6800").is_err());
+
+        let font = [0xFF; 80];
+        let chip8 = Chip8Builder::init().font(&font).build().unwrap();
+        assert_eq!(&chip8.mem()[..80], &font);
+
+        let invalid = [
+            Chip8Builder::init().mem_size(0x1800),
+            Chip8Builder::init().mem_size(0x800),
+            Chip8Builder::init().font(&[0; 5]),
+            Chip8Builder::init().rom_start(0x20),
+            Chip8Builder::init().rom_start(0xFFF),
+            Chip8Builder::init().stack_size(0),
+            Chip8Builder::init().video_size(512, 32),
+        ];
+
+        for builder in invalid {
+            assert!(builder.build().is_err());
+        }
+    }
+
+    #[test]
+    fn test_step_outcome() {
+        // Draws, waits for a key, then jumps into the unknown opcode.
+        let mut chip8 = load_chip8(&[0xD001, 0xD001, 0xF00A, 0x1208, 0x8008]);
+
+        let stats = chip8.run_frame(600);
+        assert_eq!(stats.executed, 1);
+        assert_eq!(stats.skipped, 8);
+        assert_eq!(stats.outcome, StepOutcome::WaitingForVBlank);
+
+        // Key waits go on, with the key pressed on the third tick.
+        let mut outcomes = Vec::new();
+        let stats = chip8.run_frame_with(600, |chip8, i| {
+            chip8.write_keys(&[i == 2; 16]);
+            outcomes.push(chip8.tick());
+            outcomes.last().copied()
+        });
+
+        let fault = Fault::UnknownOpcode(0x8008);
+        assert_eq!(
+            outcomes,
+            [
+                StepOutcome::Executed,
+                StepOutcome::WaitingForKey,
+                StepOutcome::WaitingForKey,
+                StepOutcome::Executed,
+                StepOutcome::Executed,
+                StepOutcome::Error(fault),
+            ]
+        );
+        assert_eq!(stats.executed, 3);
+        assert_eq!(stats.skipped, 4);
+
+        assert_eq!(chip8.tick(), StepOutcome::Halted);
+        assert_eq!(chip8.run_frame(600).skipped, 9);
+
+        // Jumps to themselves keep running, until the callback ends the frame.
+        let mut chip8 = load_chip8(&[0x1200]);
+        assert_eq!(chip8.run_frame(600).executed, 10);
+
+        let stats = chip8.run_frame_with(600, |chip8, i| (i < 2).then(|| chip8.tick()));
+        assert_eq!(stats.executed, 2);
+        assert_eq!(stats.skipped, 8);
+    }
+
+    #[test]
+    fn test_op_5xyn_9xyn() {
+        // Only 5xy0 and 9xy0 exist, as in the disassembler.
+        for opcode in [0x5121, 0x912F] {
+            let mut chip8 = load_chip8(&[opcode]);
+
+            chip8.tick();
+            assert_eq!(chip8.fault(), Some(Fault::UnknownOpcode(opcode)));
+        }
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut chip8 = load_chip8(&[0x6012, 0x8128]);
+
+        chip8.tick();
+        chip8.tick();
+        assert_eq!(chip8.fault(), Some(Fault::UnknownOpcode(0x8128)));
+        assert_eq!(chip8.pc, 0x202);
+
+        // Halted until reset.
+        chip8.tick();
+        assert_eq!(chip8.pc, 0x202);
+        chip8.reset();
+        assert_eq!(chip8.fault(), None);
+
+        let mut chip8 = load_chip8(&[0x00EE]);
+        chip8.tick();
+        assert_eq!(chip8.fault(), Some(Fault::StackUnderflow));
+
+        let mut chip8 = load_chip8(&[0x2200]);
+        for _ in 0..17 {
+            chip8.tick();
+        }
+        assert_eq!(chip8.fault(), Some(Fault::StackOverflow));
+        assert_eq!(chip8.sp, 16);
+
+        // Addresses wrap around at the end of memory.
+        let mut chip8 = load_chip8(&[0x6A1F, 0xEAA1, 0xF233]);
+        chip8.keys[0xF] = true;
+        chip8.i = 0xFFF;
+        chip8.v[2] = 123;
+        for _ in 0..3 {
+            chip8.tick();
+        }
+        assert_eq!(chip8.pc, 0x206);
+        assert_eq!(chip8.mem[0xFFF], 1);
+        assert_eq!(&chip8.mem[..2], &[2, 3]);
+        assert_eq!(chip8.fault(), None);
+    }
+
+    #[test]
+    fn test_observers() {
+        struct Recorder(Vec<Event>);
+
+        impl Observer for Recorder {
+            fn notify(&mut self, _: &Chip8, event: Event) {
+                if !matches!(event, Event::Instruction { .. }) {
+                    self.0.push(event);
+                }
+            }
+        }
+
+        let mut chip8 = load_chip8(&[
+            0x00E0, 0x6003, 0xF018, 0xA300, 0xD015, 0xF155, 0xF00A, 0x8008,
+        ]);
+        chip8.set_vblank(true);
+
+        let recorder = chip8.add_observer(Recorder(Vec::new()));
+
+        let instructions = Arc::new(AtomicU32::new(0));
+        let counter = instructions.clone();
+        chip8.add_observer(move |_: &Chip8, event| {
+            if let Event::Instruction { .. } = event {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        for _ in 0..8 {
+            chip8.tick();
+        }
+        chip8.keys[5] = true;
+        chip8.tick();
+        chip8.keys[5] = false;
+        chip8.tick();
+        chip8.tick();
+        for _ in 0..3 {
+            chip8.update_timers();
+        }
+
+        assert_eq!(
+            recorder.lock().unwrap().0,
+            [
+                Event::ScreenCleared,
+                Event::SoundStarted,
+                Event::SpriteDrawn {
+                    x: 3,
+                    y: 0,
+                    height: 5,
+                    collision: false
+                },
+                Event::MemoryWrite {
+                    addr: 0x300,
+                    value: 3
+                },
+                Event::MemoryWrite {
+                    addr: 0x301,
+                    value: 0
+                },
+                Event::KeyWaitStarted,
+                Event::KeyWaitEnded(5),
+                Event::Halted(Fault::UnknownOpcode(0x8008)),
+                Event::SoundStopped,
+            ]
+        );
+        // The fault isn't an instruction that ran.
+        assert_eq!(instructions.load(Ordering::Relaxed), 10);
+
+        // Loading a state keeps the observers.
+        let state = load_chip8(&[0x00E0]).save_state();
+        chip8.load_state(&state).unwrap();
+        chip8.tick();
+
+        assert_eq!(
+            recorder.lock().unwrap().0.last(),
+            Some(&Event::ScreenCleared)
+        );
+        assert_eq!(instructions.load(Ordering::Relaxed), 11);
+
+        // Setting a state ends the key wait the machine was in.
+        let mut chip8 = load_chip8(&[0xF00A]);
+        let recorder = chip8.add_observer(Recorder(Vec::new()));
+
+        chip8.tick();
+        chip8.set_state(&chip8.state()).unwrap();
+        chip8.tick();
+
+        assert_eq!(
+            recorder.lock().unwrap().0,
+            [Event::KeyWaitStarted, Event::KeyWaitStarted]
+        );
+    }
 }
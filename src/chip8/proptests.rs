@@ -0,0 +1,363 @@
+// Differential tests: every opcode, from random machine states and under
+// every quirks preset, must do the same as the reference below, which is
+// written straight from the instruction descriptions in chip8.rs.
+
+use oorandom::Rand32;
+use proptest::{collection::vec, option, prelude::*};
+
+use super::{
+    Chip8, Fault, Quirks, KEYS_SIZE, MEM_SIZE, NUM_REGS, STACK_SIZE, VIDEO_COLS, VIDEO_ROWS,
+};
+
+// All the state an instruction can read or write.
+#[derive(Clone, Debug, PartialEq)]
+struct Machine {
+    v: [u8; NUM_REGS],
+    i: u16,
+    pc: u16,
+    sp: u8,
+    stack: [u16; STACK_SIZE],
+    mem: Vec<u8>,
+    video: Vec<u8>,
+    keys: [bool; KEYS_SIZE],
+    delay_t: u8,
+    audio_t: u8,
+    wait_for_key: Option<u8>,
+    rng: Rand32,
+    fault: Option<Fault>,
+}
+
+impl Machine {
+    fn of(chip8: &Chip8) -> Self {
+        Self {
+            v: chip8.v,
+            i: chip8.i,
+            pc: chip8.pc,
+            sp: chip8.sp,
+            stack: chip8.stack[..].try_into().unwrap(),
+            mem: chip8.mem.to_vec(),
+            video: chip8.video.to_vec(),
+            keys: chip8.keys,
+            delay_t: chip8.delay_t,
+            audio_t: chip8.audio_t,
+            wait_for_key: chip8.wait_for_key,
+            rng: chip8.rng,
+            fault: chip8.fault,
+        }
+    }
+
+    fn chip8(&self, quirks: Quirks, vblank: bool) -> Chip8 {
+        let mut chip8 = Chip8::init(0);
+
+        chip8.v = self.v;
+        chip8.i = self.i;
+        chip8.pc = self.pc;
+        chip8.sp = self.sp;
+        chip8.stack = self.stack.to_vec();
+        chip8.mem.copy_from_slice(&self.mem);
+        chip8.video.copy_from_slice(&self.video);
+        chip8.keys = self.keys;
+        chip8.delay_t = self.delay_t;
+        chip8.audio_t = self.audio_t;
+        chip8.wait_for_key = self.wait_for_key;
+        chip8.rng = self.rng;
+        chip8.quirks = quirks;
+        chip8.vblank = vblank;
+
+        chip8
+    }
+
+    fn skip_if(&mut self, condition: bool) {
+        if condition {
+            self.pc = (self.pc + 2) % MEM_SIZE as u16;
+        }
+    }
+
+    // Back to the instruction, to run it again.
+    fn rewind(&mut self) {
+        self.pc = (self.pc + MEM_SIZE as u16 - 2) % MEM_SIZE as u16;
+    }
+
+    fn halt(&mut self, fault: Fault) {
+        self.rewind();
+        self.fault = Some(fault);
+    }
+
+    fn mem_at(&mut self, offset: usize) -> &mut u8 {
+        &mut self.mem[(self.i as usize + offset) % MEM_SIZE]
+    }
+
+    // Runs an opcode already fetched, with PC pointing past it.
+    fn execute(&mut self, opcode: u16, quirks: Quirks, vblank: bool) {
+        let x = (opcode >> 8 & 0xF) as usize;
+        let y = (opcode >> 4 & 0xF) as usize;
+        let n = (opcode & 0xF) as usize;
+        let kk = (opcode & 0xFF) as u8;
+        let nnn = opcode & 0xFFF;
+        let vx = self.v[x];
+        let vy = self.v[y];
+
+        match (opcode >> 12, kk, n) {
+            (0x0, _, _) if opcode == 0x00E0 => self.video.fill(0),
+            (0x0, _, _) if opcode == 0x00EE => {
+                if self.sp == 0 {
+                    self.halt(Fault::StackUnderflow);
+                } else {
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize] % MEM_SIZE as u16;
+                }
+            }
+            (0x0, _, _) => {}
+            (0x1, _, _) => self.pc = nnn,
+            (0x2, _, _) => {
+                if self.sp as usize == STACK_SIZE {
+                    self.halt(Fault::StackOverflow);
+                } else {
+                    self.stack[self.sp as usize] = self.pc;
+                    self.sp += 1;
+                    self.pc = nnn;
+                }
+            }
+            (0x3, _, _) => self.skip_if(vx == kk),
+            (0x4, _, _) => self.skip_if(vx != kk),
+            (0x5, _, 0x0) => self.skip_if(vx == vy),
+            (0x6, _, _) => self.v[x] = kk,
+            (0x7, _, _) => self.v[x] = vx.wrapping_add(kk),
+            (0x8, _, 0x0) => self.v[x] = vy,
+            (0x8, _, 0x1..=0x3) => {
+                self.v[x] = match n {
+                    0x1 => vx | vy,
+                    0x2 => vx & vy,
+                    _ => vx ^ vy,
+                };
+
+                if quirks.vf_reset {
+                    self.v[0xF] = 0;
+                }
+            }
+            (0x8, _, 0x4) => {
+                let sum = vx as u16 + vy as u16;
+                self.v[x] = sum as u8;
+                self.v[0xF] = (sum > 0xFF) as u8;
+            }
+            (0x8, _, 0x5) => {
+                self.v[x] = vx.wrapping_sub(vy);
+                self.v[0xF] = (vx >= vy) as u8;
+            }
+            (0x8, _, 0x6) => {
+                let value = if quirks.shifting { vx } else { vy };
+                self.v[x] = value / 2;
+                self.v[0xF] = value % 2;
+            }
+            (0x8, _, 0x7) => {
+                self.v[x] = vy.wrapping_sub(vx);
+                self.v[0xF] = (vy >= vx) as u8;
+            }
+            (0x8, _, 0xE) => {
+                let value = if quirks.shifting { vx } else { vy };
+                self.v[x] = value.wrapping_mul(2);
+                self.v[0xF] = (value >= 0x80) as u8;
+            }
+            (0x9, _, 0x0) => self.skip_if(vx != vy),
+            (0xA, _, _) => self.i = nnn,
+            (0xB, _, _) => {
+                let offset = if quirks.jumping { vx } else { self.v[0] };
+                self.pc = (nnn + offset as u16) % MEM_SIZE as u16;
+            }
+            (0xC, _, _) => self.v[x] = self.rng.rand_range(0..256) as u8 & kk,
+            (0xD, _, _) if quirks.display_wait && !vblank => self.rewind(),
+            (0xD, _, _) => {
+                let mut collision = false;
+
+                for row in 0..n {
+                    let sprite = *self.mem_at(row);
+
+                    for col in 0..8 {
+                        let px = vx as usize % VIDEO_COLS + col;
+                        let py = vy as usize % VIDEO_ROWS + row;
+
+                        let off_screen = px >= VIDEO_COLS || py >= VIDEO_ROWS;
+                        if sprite & (0x80 >> col) == 0 || quirks.clipping && off_screen {
+                            continue;
+                        }
+
+                        let pixel = &mut self.video[py % VIDEO_ROWS * VIDEO_COLS + px % VIDEO_COLS];
+                        collision |= *pixel == 1;
+                        *pixel ^= 1;
+                    }
+                }
+
+                self.v[0xF] = collision as u8;
+            }
+            (0xE, 0x9E, _) => self.skip_if(self.keys[vx as usize % KEYS_SIZE]),
+            (0xE, 0xA1, _) => self.skip_if(!self.keys[vx as usize % KEYS_SIZE]),
+            (0xF, 0x07, _) => self.v[x] = self.delay_t,
+            // Waits for a key to be pressed and released.
+            (0xF, 0x0A, _) => match self.wait_for_key {
+                Some(key) if !self.keys[key as usize] => {
+                    self.v[x] = key;
+                    self.wait_for_key = None;
+                }
+                Some(_) => self.rewind(),
+                None => {
+                    self.wait_for_key = self.keys.iter().position(|&k| k).map(|k| k as u8);
+                    self.rewind();
+                }
+            },
+            (0xF, 0x15, _) => self.delay_t = vx,
+            (0xF, 0x18, _) => self.audio_t = vx,
+            (0xF, 0x1E, _) => {
+                self.i = self.i.wrapping_add(vx as u16);
+                self.v[0xF] = (self.i > 0xFFF) as u8;
+            }
+            (0xF, 0x29, _) => self.i = vx as u16 * 5,
+            (0xF, 0x33, _) => {
+                *self.mem_at(0) = vx / 100;
+                *self.mem_at(1) = vx / 10 % 10;
+                *self.mem_at(2) = vx % 10;
+            }
+            (0xF, 0x55, _) => {
+                for r in 0..=x {
+                    *self.mem_at(r) = self.v[r];
+                }
+
+                if quirks.memory {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            (0xF, 0x65, _) => {
+                for r in 0..=x {
+                    self.v[r] = *self.mem_at(r);
+                }
+
+                if quirks.memory {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            _ => self.halt(Fault::UnknownOpcode(opcode)),
+        }
+    }
+}
+
+// The 35 instructions, as the fixed bits and the bits left to chance.
+const INSTRUCTIONS: [(u16, u16); 35] = [
+    (0x0000, 0x0FFF), // SYS addr
+    (0x00E0, 0x0000), // CLS
+    (0x00EE, 0x0000), // RET
+    (0x1000, 0x0FFF), // JP addr
+    (0x2000, 0x0FFF), // CALL addr
+    (0x3000, 0x0FFF), // SE Vx, byte
+    (0x4000, 0x0FFF), // SNE Vx, byte
+    (0x5000, 0x0FF0), // SE Vx, Vy
+    (0x6000, 0x0FFF), // LD Vx, byte
+    (0x7000, 0x0FFF), // ADD Vx, byte
+    (0x8000, 0x0FF0), // LD Vx, Vy
+    (0x8001, 0x0FF0), // OR Vx, Vy
+    (0x8002, 0x0FF0), // AND Vx, Vy
+    (0x8003, 0x0FF0), // XOR Vx, Vy
+    (0x8004, 0x0FF0), // ADD Vx, Vy
+    (0x8005, 0x0FF0), // SUB Vx, Vy
+    (0x8006, 0x0FF0), // SHR Vx, Vy
+    (0x8007, 0x0FF0), // SUBN Vx, Vy
+    (0x800E, 0x0FF0), // SHL Vx, Vy
+    (0x9000, 0x0FF0), // SNE Vx, Vy
+    (0xA000, 0x0FFF), // LD I, addr
+    (0xB000, 0x0FFF), // JP V0, addr
+    (0xC000, 0x0FFF), // RND Vx, byte
+    (0xD000, 0x0FFF), // DRW Vx, Vy, nibble
+    (0xE09E, 0x0F00), // SKP Vx
+    (0xE0A1, 0x0F00), // SKNP Vx
+    (0xF007, 0x0F00), // LD Vx, DT
+    (0xF00A, 0x0F00), // LD Vx, K
+    (0xF015, 0x0F00), // LD DT, Vx
+    (0xF018, 0x0F00), // LD ST, Vx
+    (0xF01E, 0x0F00), // ADD I, Vx
+    (0xF029, 0x0F00), // LD F, Vx
+    (0xF033, 0x0F00), // LD B, Vx
+    (0xF055, 0x0F00), // LD [I], Vx
+    (0xF065, 0x0F00), // LD Vx, [I]
+];
+
+fn opcode() -> impl Strategy<Value = u16> {
+    let instruction = (0..INSTRUCTIONS.len(), any::<u16>()).prop_map(|(n, bits)| {
+        let (fixed, free) = INSTRUCTIONS[n];
+        fixed | bits & free
+    });
+
+    // 5xyn and 9xyn run as 5xy0 and 9xy0 whatever n is, so they are left
+    // out of the other opcodes.
+    let other = any::<u16>().prop_filter("5xyn or 9xyn", |opcode| {
+        !matches!(opcode >> 12, 0x5 | 0x9) || opcode & 0xF == 0
+    });
+
+    prop_oneof![9 => instruction, 1 => other]
+}
+
+prop_compose! {
+    fn registers()(
+        v in any::<[u8; NUM_REGS]>(),
+        i in any::<u16>(),
+        pc in 0..MEM_SIZE as u16,
+        sp in 0..=STACK_SIZE as u8,
+        stack in any::<[u16; STACK_SIZE]>(),
+        delay_t in any::<u8>(),
+        audio_t in any::<u8>(),
+    ) -> (([u8; NUM_REGS], u16, u16, u8, [u16; STACK_SIZE]), (u8, u8)) {
+        ((v, i, pc, sp, stack), (delay_t, audio_t))
+    }
+}
+
+prop_compose! {
+    fn machine()(
+        ((v, i, pc, sp, stack), (delay_t, audio_t)) in registers(),
+        mem in vec(any::<u8>(), MEM_SIZE),
+        video in vec(0..=1u8, VIDEO_COLS * VIDEO_ROWS),
+        keys in any::<[bool; KEYS_SIZE]>(),
+        wait_for_key in option::of(0..KEYS_SIZE as u8),
+        rng_seed in any::<u64>(),
+    ) -> Machine {
+        Machine {
+            v,
+            i,
+            pc,
+            sp,
+            stack,
+            mem,
+            video,
+            keys,
+            delay_t,
+            audio_t,
+            wait_for_key,
+            rng: Rand32::new(rng_seed),
+            fault: None,
+        }
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn test_execute_matches_reference(
+        machine in machine(),
+        opcode in opcode(),
+        vblank in any::<bool>(),
+    ) {
+        for (name, quirks) in Quirks::PRESETS {
+            let mut chip8 = machine.chip8(quirks, vblank);
+
+            let mut expected = machine.clone();
+            expected.execute(opcode, quirks, vblank);
+
+            chip8.execute(opcode);
+
+            prop_assert_eq!(
+                Machine::of(&chip8),
+                expected,
+                "{:04X} with the {} quirks",
+                opcode,
+                name
+            );
+        }
+    }
+}
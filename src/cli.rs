@@ -0,0 +1,342 @@
+use crate::{
+    chip8::Quirks,
+    config::Config,
+    filter::Filter,
+    netplay::Role,
+    palette::Palette,
+    reload::{self, Reload},
+};
+
+pub const HELP: &str = "\
+Usage: lrchip8 [COMMAND] ROM [OPTIONS]
+
+Commands:
+    run         Run the rom in a window (default)
+    info        Show the rom's hash and database entry
+    disasm      Disassemble the rom
+    cfg         Print the rom's control flow graph in Graphviz format
+    decompile   Decompile the rom to Octo source
+    bench       Run the rom as fast as possible and report the speed
+    headless    Run the rom without a window, e.g. to record it
+
+Options:
+    --ips N                   Instructions per second
+    --scale N                 Window scale factor
+    --seed N                  Random number generator seed (default: current time)
+    --palette NAME            Use a named palette or custom colors, e.g. \"#000000,#FFFFFF\"
+    --filter FILTER           Reduce flicker: decay[:0.6], max[:2] or vblank[:1]
+    --quirks QUIRKS           Quirks preset and changes, e.g. \"schip,-clipping\"
+    --fullscreen              Start in fullscreen
+    --mute                    Disable sound
+    --frames N                Frames to run (bench and headless)
+    --watch MODE              Reload the rom when its file changes: reset or patch
+    --assembler CMD           Octo assembler for .8o sources (default: octo)
+    --host PORT               Host a netplay game, waiting for the other player on PORT
+    --connect HOST:PORT       Join a netplay game
+    --delay N                 Netplay input delay in frames (default: 2)
+    --script PATH             Run a Rhai script with the rom (run and headless)
+    --symbols PATH            Name addresses from a symbol file (default: ROM.sym if found)
+    --rpc PORT|PATH           Run without a window, driven by JSON-RPC requests on a
+                              localhost port or a Unix socket
+    --record-audio out.wav    Record the beeper to a WAV file
+    --record-video out.gif    Record the screen to a GIF (or out.png for a PNG sequence)
+    -h, --help                Show this help";
+
+// Netplay input delay, in frames.
+const DEFAULT_DELAY: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Run,
+    Info,
+    Disasm,
+    Cfg,
+    Decompile,
+    Bench,
+    Headless,
+    Help,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub command: Command,
+    pub rom_path: String,
+    pub record_audio: Option<String>,
+    pub record_video: Option<String>,
+    pub frames: Option<u64>,
+    pub seed: Option<u64>,
+    pub ips: Option<u32>,
+    pub scale_factor: Option<usize>,
+    pub palette: Option<Palette>,
+    pub filter: Option<Filter>,
+    pub quirks: Option<Quirks>,
+    pub fullscreen: bool,
+    pub mute: bool,
+    pub watch: Option<Reload>,
+    pub assembler: String,
+    pub netplay: Option<Role>,
+    pub delay: u8,
+    pub rpc: Option<String>,
+    pub script: Option<String>,
+    pub symbols: Option<String>,
+}
+
+impl Options {
+    // Command line options take precedence over the config file.
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(ips) = self.ips {
+            config.ips = ips;
+        }
+
+        if let Some(scale_factor) = self.scale_factor {
+            config.scale_factor = scale_factor;
+        }
+
+        if let Some(palette) = &self.palette {
+            config.palette = palette.clone();
+        }
+
+        if let Some(filter) = self.filter {
+            config.filter = filter;
+        }
+
+        if let Some(quirks) = self.quirks {
+            config.quirks = quirks;
+        }
+    }
+}
+
+pub fn parse_options<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut command = None;
+    let mut rom_path = None;
+    let mut record_audio = None;
+    let mut record_video = None;
+    let mut frames = None;
+    let mut seed = None;
+    let mut ips = None;
+    let mut scale_factor = None;
+    let mut palette = None;
+    let mut filter = None;
+    let mut quirks = None;
+    let mut fullscreen = false;
+    let mut mute = false;
+    let mut watch = None;
+    let mut assembler = reload::DEFAULT_ASSEMBLER.to_string();
+    let mut netplay = None;
+    let mut delay = DEFAULT_DELAY;
+    let mut rpc = None;
+    let mut script = None;
+    let mut symbols = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => command = Some(Command::Help),
+            "--record-audio" => {
+                record_audio = Some(args.next().ok_or("Please inform a wav path.")?);
+            }
+            "--record-video" => {
+                record_video = Some(args.next().ok_or("Please inform a gif or png path.")?);
+            }
+            "--frames" => {
+                let n = args.next().ok_or("Please inform a number of frames.")?;
+                frames = Some(n.parse().map_err(|_| "Invalid number of frames.")?);
+            }
+            "--seed" => {
+                let n = args.next().ok_or("Please inform a seed.")?;
+                seed = Some(n.parse().map_err(|_| "Invalid seed.")?);
+            }
+            "--ips" => {
+                let n = args
+                    .next()
+                    .ok_or("Please inform the instructions per second.")?;
+                ips = Some(n.parse().map_err(|_| "Invalid instructions per second.")?);
+            }
+            "--scale" => {
+                let n = args.next().ok_or("Please inform a scale factor.")?;
+                scale_factor = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|&scale| scale >= 1)
+                        .ok_or("Invalid scale factor.")?,
+                );
+            }
+            "--palette" => {
+                palette = Some(Palette::parse(
+                    &args.next().ok_or("Please inform a palette.")?,
+                )?);
+            }
+            "--filter" => {
+                filter = Some(Filter::parse(
+                    &args.next().ok_or("Please inform a filter.")?,
+                )?);
+            }
+            "--quirks" => {
+                quirks = Some(Quirks::parse(
+                    &args.next().ok_or("Please inform the quirks.")?,
+                )?);
+            }
+            "--watch" => {
+                watch = Some(Reload::parse(
+                    &args.next().ok_or("Please inform a reload mode.")?,
+                )?);
+            }
+            "--assembler" => {
+                assembler = args.next().ok_or("Please inform an assembler command.")?;
+            }
+            "--host" => {
+                let port = args.next().ok_or("Please inform a port.")?;
+                netplay = Some(Role::Host(port.parse().map_err(|_| "Invalid port.")?));
+            }
+            "--connect" => {
+                netplay = Some(Role::Connect(
+                    args.next().ok_or("Please inform an address.")?,
+                ));
+            }
+            "--delay" => {
+                let n = args.next().ok_or("Please inform an input delay.")?;
+                delay = n.parse().map_err(|_| "Invalid input delay.")?;
+            }
+            "--rpc" => {
+                rpc = Some(args.next().ok_or("Please inform a port or socket path.")?);
+            }
+            "--script" => {
+                script = Some(args.next().ok_or("Please inform a script path.")?);
+            }
+            "--symbols" => {
+                symbols = Some(args.next().ok_or("Please inform a symbol file path.")?);
+            }
+            "--fullscreen" => fullscreen = true,
+            "--mute" => mute = true,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option: {}", arg)),
+            // The command can be left out: "lrchip8 pong.ch8" runs the rom.
+            _ if command.is_none() && rom_path.is_none() => {
+                command = Some(match arg.as_str() {
+                    "run" => Command::Run,
+                    "info" => Command::Info,
+                    "disasm" => Command::Disasm,
+                    "cfg" => Command::Cfg,
+                    "decompile" => Command::Decompile,
+                    "bench" => Command::Bench,
+                    "headless" => Command::Headless,
+                    _ => {
+                        rom_path = Some(arg);
+                        Command::Run
+                    }
+                });
+            }
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+
+    let command = command.unwrap_or(Command::Help);
+
+    if command == Command::Help {
+        return Err(HELP.to_string());
+    }
+
+    if command == Command::Headless && frames.is_none() {
+        return Err("Please inform a number of frames.".to_string());
+    }
+
+    if (netplay.is_some() || rpc.is_some()) && command != Command::Run {
+        return Err("Netplay and RPC only work with the run command.".to_string());
+    }
+
+    if netplay.is_some() && rpc.is_some() {
+        return Err("Netplay and RPC can't be used together.".to_string());
+    }
+
+    if script.is_some() && !matches!(command, Command::Run | Command::Headless) {
+        return Err("Scripts only work with the run and headless commands.".to_string());
+    }
+
+    // Scripts change the state on one side only.
+    if script.is_some() && (netplay.is_some() || rpc.is_some()) {
+        return Err("Scripts can't be used with netplay or RPC.".to_string());
+    }
+
+    Ok(Options {
+        command,
+        rom_path: rom_path.ok_or("Please inform a rom path.")?,
+        record_audio,
+        record_video,
+        frames,
+        seed,
+        ips,
+        scale_factor,
+        palette,
+        filter,
+        quirks,
+        fullscreen,
+        mute,
+        watch,
+        assembler,
+        netplay,
+        delay,
+        rpc,
+        script,
+        symbols,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_options, Command, HELP};
+    use crate::{netplay::Role, reload::Reload};
+
+    fn parse(args: &str) -> Result<super::Options, String> {
+        parse_options(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let options = parse("roms/pong.ch8 --ips 600 --mute").unwrap();
+        assert_eq!(options.command, Command::Run);
+        assert_eq!(options.rom_path, "roms/pong.ch8");
+        assert_eq!(options.ips, Some(600));
+        assert!(options.mute);
+        assert!(!options.fullscreen);
+
+        let options = parse("headless roms/pong.ch8 --frames 60 --seed 7").unwrap();
+        assert_eq!(options.command, Command::Headless);
+        assert_eq!(options.frames, Some(60));
+        assert_eq!(options.seed, Some(7));
+        assert_eq!(options.watch, None);
+        assert_eq!(options.assembler, "octo");
+
+        let options = parse("game.8o --watch patch --assembler octo-cli").unwrap();
+        assert_eq!(options.watch, Some(Reload::Patch));
+        assert_eq!(options.assembler, "octo-cli");
+
+        let options = parse("pong.ch8 --connect localhost:8088 --delay 4").unwrap();
+        assert_eq!(
+            options.netplay,
+            Some(Role::Connect("localhost:8088".to_string()))
+        );
+        assert_eq!(options.delay, 4);
+        assert!(parse("pong.ch8 --host http").is_err());
+        assert!(parse("pong.ch8 --scale 0").is_err());
+        assert!(parse("bench pong.ch8 --rpc 9000").is_err());
+
+        let options = parse("headless pong.ch8 --frames 1 --script bot.rhai").unwrap();
+        assert_eq!(options.script, Some("bot.rhai".to_string()));
+        assert!(parse("pong.ch8 --script bot.rhai --host 8088").is_err());
+
+        let options = parse("disasm pong.ch8 --symbols pong.txt").unwrap();
+        assert_eq!(options.symbols, Some("pong.txt".to_string()));
+
+        assert_eq!(parse("disasm a.ch8").unwrap().command, Command::Disasm);
+        assert_eq!(parse("cfg a.ch8").unwrap().command, Command::Cfg);
+        assert_eq!(
+            parse("decompile a.ch8").unwrap().command,
+            Command::Decompile
+        );
+        assert_eq!(parse("--help"), Err(HELP.to_string()));
+        assert_eq!(parse(""), Err(HELP.to_string()));
+        assert!(parse("headless a.ch8").is_err());
+        assert!(parse("run").is_err());
+        assert!(parse("a.ch8 b.ch8").is_err());
+        assert!(parse("a.ch8 --speed 2").is_err());
+    }
+}
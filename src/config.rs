@@ -0,0 +1,274 @@
+use std::{env, fs, io, path::PathBuf};
+
+use sha1::{Digest, Sha1};
+use toml::{Table, Value};
+
+use crate::{
+    audio::{Tone, Waveform},
+    chip8::Quirks,
+    filter::Filter,
+    keymap::KeyMap,
+    palette::Palette,
+    romdb::RomInfo,
+};
+
+const CONFIG_FILE: &str = "config.toml";
+
+// Settings from the config file. Sections under [rom.<sha1>] override the
+// global ones for that rom only:
+//
+//   ips = 800
+//   scale = 12
+//   palette = "amber"            # or ["#000000", "#FFFFFF"]
+//   filter = "vblank:1"
+//   quirks = "chip8"             # or { shifting = true, ... }
+//
+//   [audio]
+//   freq = 440
+//   volume = 0.25
+//   waveform = "pulse:0.25"      # square, pulse[:duty] or triangle
+//
+//   [keys]                       # see KeyMap
+//   5 = ["W", "Up"]
+//
+//   [rom.<sha1>]
+//   ips = 1000
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub ips: u32,
+    pub scale_factor: usize,
+    pub palette: Palette,
+    pub filter: Filter,
+    pub quirks: Quirks,
+    pub tone: Tone,
+    pub keymap: KeyMap,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            ips: 800,
+            scale_factor: 12,
+            palette: Palette::default(),
+            filter: Filter::None,
+            quirks: Quirks::default(),
+            tone: Tone::default(),
+            keymap: KeyMap::default(),
+        }
+    }
+}
+
+impl Config {
+    // Loads the config file, if any, for the rom with the given hash. What is
+    // known about the rom goes between the global and the rom settings.
+    pub fn load(rom_hash: &str, rom_info: Option<&RomInfo>) -> Result<Self, String> {
+        let mut config = Config::default();
+
+        let path = match config_dir() {
+            Some(dir) => dir.join(CONFIG_FILE),
+            None => {
+                if let Some(info) = rom_info {
+                    config.apply_rom_info(info);
+                }
+
+                return Ok(config);
+            }
+        };
+
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+
+        let error = |e: String| format!("{}: {}", path.display(), e);
+        let table = text.parse::<Table>().map_err(|e| error(e.to_string()))?;
+
+        config.apply(&table).map_err(error)?;
+
+        if let Some(info) = rom_info {
+            config.apply_rom_info(info);
+        }
+
+        if let Some(Value::Table(rom)) = table.get("rom").and_then(|roms| roms.get(rom_hash)) {
+            config.apply(rom).map_err(error)?;
+        }
+
+        Ok(config)
+    }
+
+    // Applies the recommended settings for a rom from the database.
+    pub fn apply_rom_info(&mut self, info: &RomInfo) {
+        self.quirks = info.quirks;
+        self.ips = info.tickrate * 60;
+
+        if info.colors.len() >= 2 {
+            if let Ok(palette) = Palette::parse(&info.colors.join(",")) {
+                self.palette = palette;
+            }
+        }
+    }
+
+    // Overrides the settings present in the table.
+    pub fn apply(&mut self, table: &Table) -> Result<(), String> {
+        for (key, value) in table {
+            match key.as_str() {
+                "ips" => self.ips = integer(key, value)?,
+                "scale" => {
+                    self.scale_factor = integer(key, value)?;
+
+                    if self.scale_factor < 1 {
+                        return Err(format!("Invalid {}: {}", key, value));
+                    }
+                }
+                "palette" => {
+                    self.palette = match value {
+                        Value::Array(colors) => Palette::parse(&strings(key, colors)?.join(","))?,
+                        _ => Palette::parse(string(key, value)?)?,
+                    }
+                }
+                "filter" => self.filter = Filter::parse(string(key, value)?)?,
+                "quirks" => match value {
+                    Value::Table(flags) => {
+                        for (flag, on) in flags {
+                            let on = on
+                                .as_bool()
+                                .ok_or(format!("Expected a boolean: {}", flag))?;
+                            self.quirks.set(flag, on)?;
+                        }
+                    }
+                    _ => self.quirks = Quirks::parse(string(key, value)?)?,
+                },
+                "audio" => {
+                    let audio = value.as_table().ok_or("Expected an [audio] table.")?;
+
+                    for (key, value) in audio {
+                        match key.as_str() {
+                            "freq" => self.tone.freq = float(key, value)?,
+                            "volume" => {
+                                self.tone.volume = float(key, value)?;
+
+                                if !(0.0..=1.0).contains(&self.tone.volume) {
+                                    return Err(format!(
+                                        "Expected a volume from 0 to 1: {}",
+                                        value
+                                    ));
+                                }
+                            }
+                            "waveform" => {
+                                self.tone.waveform = Waveform::parse(string(key, value)?)?
+                            }
+                            _ => return Err(format!("Unknown audio setting: {}", key)),
+                        }
+                    }
+                }
+                // Handled by the keymap, and by load.
+                "keys" | "buttons" | "rom" => {}
+                _ => return Err(format!("Unknown setting: {}", key)),
+            }
+        }
+
+        self.keymap.apply(table)
+    }
+}
+
+pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("lrchip8"))
+}
+
+// Lowercase hex SHA-1 of the rom, identifying it in config sections.
+pub fn rom_hash(rom: &[u8]) -> String {
+    Sha1::digest(rom)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn integer<T: TryFrom<i64>>(key: &str, value: &Value) -> Result<T, String> {
+    value
+        .as_integer()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or(format!("Invalid {}: {}", key, value))
+}
+
+fn float(key: &str, value: &Value) -> Result<f32, String> {
+    match value {
+        Value::Float(n) => Ok(*n as f32),
+        Value::Integer(n) => Ok(*n as f32),
+        _ => Err(format!("Invalid {}: {}", key, value)),
+    }
+}
+
+fn string<'a>(key: &str, value: &'a Value) -> Result<&'a str, String> {
+    value
+        .as_str()
+        .ok_or(format!("Expected a string for {}: {}", key, value))
+}
+
+fn strings(key: &str, values: &[Value]) -> Result<Vec<String>, String> {
+    values
+        .iter()
+        .map(|value| string(key, value).map(str::to_string))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use toml::Table;
+
+    use super::{rom_hash, Config};
+    use crate::{audio::Waveform, chip8::Quirks};
+
+    #[test]
+    fn test_rom_hash() {
+        assert_eq!(rom_hash(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn test_config_apply() {
+        let table = r##"
+            ips = 1000
+            palette = ["#000000", "#FFFFFF"]
+            quirks = "chip8"
+
+            [audio]
+            freq = 440
+            waveform = "triangle"
+
+            [rom.a9993e364706816aba3e25717850c26c9cd0d89d]
+            ips = 500
+            quirks = { clipping = false }
+        "##
+        .parse::<Table>()
+        .unwrap();
+
+        let mut config = Config::default();
+        config.apply(&table).unwrap();
+
+        assert_eq!(config.ips, 1000);
+        assert_eq!(config.scale_factor, 12);
+        assert_eq!(config.palette.name, "custom");
+        assert_eq!(config.quirks, Quirks::CHIP8);
+        assert_eq!(config.tone.freq, 440.0);
+        assert_eq!(config.tone.waveform, Waveform::Triangle);
+
+        config
+            .apply(
+                table["rom"]["a9993e364706816aba3e25717850c26c9cd0d89d"]
+                    .as_table()
+                    .unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(config.ips, 500);
+        assert!(!config.quirks.clipping);
+        assert!(config.quirks.vf_reset);
+
+        for invalid in ["speed = 1", "scale = 0", "audio = { volume = 1.5 }"] {
+            assert!(config.apply(&invalid.parse::<Table>().unwrap()).is_err());
+        }
+    }
+}
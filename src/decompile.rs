@@ -0,0 +1,680 @@
+// Decompiles roms to Octo source that assembles back to the same bytes:
+//
+//     lrchip8 decompile pong.ch8 > pong.8o
+//
+// Code found by the control flow analysis is emitted as statements, the rest
+// as raw bytes. Skips over forward jumps become if/begin/else/end blocks and
+// backward jumps loop/again, where they nest. Labels take the symbol names,
+// if any, or are named after what points to them: main, subroutines, sprites,
+// data and jump targets.
+
+use std::{collections::BTreeMap, fmt::Write, ops::Range};
+
+use crate::{
+    dec_addr, dec_byte, dec_nibble, dec_x, dec_y,
+    flow::{DataKind, Flow},
+    symbols::Symbols,
+};
+
+// Raw bytes per line.
+const BYTES_PER_LINE: usize = 8;
+
+enum Item {
+    Code(u16, u16),
+    Bytes(u16, Vec<u8>),
+}
+
+impl Item {
+    fn addr(&self) -> u16 {
+        match *self {
+            Item::Code(addr, _) | Item::Bytes(addr, _) => addr,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    // The skip opcode: the block runs when the skip is taken.
+    If(u16),
+    Loop,
+}
+
+// An if, from the skip to the end of the last body, or a loop, from its first
+// instruction to the jump back. The instructions it replaces are consumed.
+struct Construct {
+    kind: Kind,
+    range: Range<u16>,
+    bodies: Vec<Range<u16>>,
+    consumed: Vec<u16>,
+}
+
+impl Construct {
+    fn nests_in(&self, other: &Construct) -> bool {
+        other
+            .bodies
+            .iter()
+            .any(|body| body.start <= self.range.start && self.range.end <= body.end)
+    }
+
+    fn fits_with(&self, other: &Construct) -> bool {
+        let disjoint = self.range.end <= other.range.start || other.range.end <= self.range.start;
+        let shares_code = self.consumed.iter().any(|a| other.consumed.contains(a));
+
+        !shares_code && (disjoint || self.nests_in(other) || other.nests_in(self))
+    }
+}
+
+// The rom must fit in memory, as with Chip8::load.
+pub fn decompile(rom: &[u8], start_addr: u16, symbols: &Symbols) -> String {
+    Decompiler::init(rom, start_addr, symbols).source()
+}
+
+struct Decompiler {
+    items: Vec<Item>,
+    end_addr: u16,
+    constructs: Vec<Construct>,
+    labels: BTreeMap<u16, String>,
+}
+
+impl Decompiler {
+    fn init(rom: &[u8], start_addr: u16, symbols: &Symbols) -> Self {
+        let flow = Flow::analyze(rom, start_addr);
+        let end_addr = start_addr + rom.len() as u16;
+
+        let mut code = BTreeMap::new();
+
+        for block in flow.blocks() {
+            for addr in (block.start..block.end).step_by(2) {
+                let offset = (addr - start_addr) as usize;
+                code.insert(addr, (rom[offset] as u16) << 8 | rom[offset + 1] as u16);
+            }
+        }
+
+        // Raw bytes are split where labels may go.
+        let mut splits: Vec<u16> = code.values().filter_map(|&op| target(op)).collect();
+        splits.extend(flow.data().iter().map(|data| data.addr + data.len));
+
+        let mut items = Vec::new();
+        let mut addr = start_addr;
+
+        while addr < end_addr {
+            if let Some(&opcode) = code.get(&addr) {
+                items.push(Item::Code(addr, opcode));
+                addr += 2;
+                continue;
+            }
+
+            let start = addr;
+            addr += 1;
+
+            while addr < end_addr
+                && !code.contains_key(&addr)
+                && !splits.contains(&addr)
+                && addr - start < BYTES_PER_LINE as u16
+            {
+                addr += 1;
+            }
+
+            let bytes = rom[(start - start_addr) as usize..(addr - start_addr) as usize].to_vec();
+            items.push(Item::Bytes(start, bytes));
+        }
+
+        let mut decompiler = Self {
+            items,
+            end_addr,
+            constructs: Vec::new(),
+            labels: BTreeMap::new(),
+        };
+        decompiler.find_constructs();
+        decompiler.name_labels(&flow, start_addr, symbols);
+
+        decompiler
+    }
+
+    // Candidates are taken outermost first, as long as they nest with the
+    // ones already taken.
+    fn find_constructs(&mut self) {
+        let code: BTreeMap<u16, u16> = self
+            .items
+            .iter()
+            .filter_map(|item| match *item {
+                Item::Code(addr, opcode) => Some((addr, opcode)),
+                Item::Bytes(..) => None,
+            })
+            .collect();
+
+        let jump = |addr| {
+            code.get(&addr)
+                .filter(|&op| op & 0xF000 == 0x1000)
+                .map(|&op| dec_addr!(op))
+        };
+        let referenced: Vec<u16> = code.values().filter_map(|&op| target(op)).collect();
+
+        let mut candidates = Vec::new();
+
+        for (&addr, &opcode) in &code {
+            if let Some(to) = jump(addr).filter(|&to| to <= addr && self.is_boundary(to)) {
+                candidates.push(Construct {
+                    kind: Kind::Loop,
+                    range: to..addr + 2,
+                    bodies: vec![Range {
+                        start: to,
+                        end: addr,
+                    }],
+                    consumed: vec![addr],
+                });
+            }
+
+            // The jump after the skip can't be given a label.
+            if skip_condition(opcode).is_none() || referenced.contains(&(addr + 2)) {
+                continue;
+            }
+
+            let Some(then_end) = jump(addr + 2).filter(|&to| to > addr + 4 && self.is_boundary(to))
+            else {
+                continue;
+            };
+
+            let else_addr = then_end - 2;
+
+            if let Some(end) = jump(else_addr).filter(|&to| to >= then_end && self.is_boundary(to))
+            {
+                if else_addr > addr + 4 {
+                    candidates.push(Construct {
+                        kind: Kind::If(opcode),
+                        range: addr..end,
+                        bodies: vec![addr + 4..else_addr, then_end..end],
+                        consumed: vec![addr, addr + 2, else_addr],
+                    });
+                }
+            }
+
+            candidates.push(Construct {
+                kind: Kind::If(opcode),
+                range: addr..then_end,
+                bodies: vec![Range {
+                    start: addr + 4,
+                    end: then_end,
+                }],
+                consumed: vec![addr, addr + 2],
+            });
+        }
+
+        candidates.sort_by_key(|c| (c.range.start, u16::MAX - c.range.end));
+
+        for candidate in candidates {
+            if self.constructs.iter().all(|c| c.fits_with(&candidate)) {
+                self.constructs.push(candidate);
+            }
+        }
+    }
+
+    // Only addresses still used by a statement need a label. The rom starts
+    // at main whatever its symbol, as Octo expects.
+    fn name_labels(&mut self, flow: &Flow, start_addr: u16, symbols: &Symbols) {
+        self.labels.insert(start_addr, "main".to_string());
+
+        for item in &self.items {
+            let Item::Code(addr, opcode) = *item else {
+                continue;
+            };
+
+            let Some(to) = target(opcode) else {
+                continue;
+            };
+
+            if self.is_consumed(addr) || !self.is_boundary(to) || self.labels.contains_key(&to) {
+                continue;
+            }
+
+            let data = flow.data().iter().find(|data| data.addr == to);
+
+            let prefix = match data.map(|data| data.kind) {
+                _ if flow.subroutines().contains(&to) => "sub",
+                Some(DataKind::Sprite) => "sprite",
+                Some(DataKind::Data) => "data",
+                None => "label",
+            };
+
+            let name = match symbols.name(to) {
+                Some(name) if !self.labels.values().any(|label| label == name) => name.to_string(),
+                _ => format!("{}_{:03X}", prefix, to),
+            };
+
+            self.labels.insert(to, name);
+        }
+    }
+
+    fn source(&self) -> String {
+        let mut out = String::new();
+        let mut depth = 0;
+
+        let line = |out: &mut String, depth: usize, text: &str| {
+            writeln!(out, "{}{}", "\t".repeat(depth + 1), text).unwrap();
+        };
+
+        let ends = self.items.iter().map(Item::addr).chain([self.end_addr]);
+
+        for (n, addr) in ends.enumerate() {
+            let mut closing: Vec<&Construct> = self
+                .constructs
+                .iter()
+                .filter(|c| matches!(c.kind, Kind::If(_)) && c.range.end == addr)
+                .collect();
+            closing.sort_by_key(|c| u16::MAX - c.range.start);
+
+            for _ in closing {
+                depth -= 1;
+                line(&mut out, depth, "end");
+            }
+
+            if let Some(name) = self.labels.get(&addr) {
+                writeln!(out, ": {}", name).unwrap();
+            }
+
+            let mut opening: Vec<&Construct> = self
+                .constructs
+                .iter()
+                .filter(|c| c.range.start == addr)
+                .collect();
+            opening.sort_by_key(|c| (c.kind != Kind::Loop, u16::MAX - c.range.end));
+
+            for construct in opening {
+                match construct.kind {
+                    Kind::Loop => line(&mut out, depth, "loop"),
+                    Kind::If(opcode) => {
+                        let text = format!("if {} begin", skip_condition(opcode).unwrap());
+                        line(&mut out, depth, &text);
+                    }
+                }
+
+                depth += 1;
+            }
+
+            let Some(item) = self.items.get(n) else {
+                break;
+            };
+
+            match item {
+                Item::Bytes(_, bytes) => line(&mut out, depth, &raw_bytes(bytes)),
+                Item::Code(addr, opcode) => match self.consumed_by(*addr) {
+                    Some(c) if c.kind == Kind::Loop => {
+                        depth -= 1;
+                        line(&mut out, depth, "again");
+                    }
+                    Some(c) if c.bodies.len() == 2 && c.bodies[0].end == *addr => {
+                        line(&mut out, depth - 1, "else");
+                    }
+                    Some(_) => {}
+                    None => line(&mut out, depth, &self.statement(*opcode)),
+                },
+            }
+        }
+
+        out
+    }
+
+    fn statement(&self, opcode: u16) -> String {
+        let x = dec_x!(opcode);
+        let y = dec_y!(opcode);
+        let kk = dec_byte!(opcode);
+        let nnn = dec_addr!(opcode);
+        let raw = || raw_bytes(&opcode.to_be_bytes());
+
+        if let Some(condition) = skip_condition(opcode) {
+            return format!("if {} then", negate(&condition));
+        }
+
+        match opcode & 0xF000 {
+            0x0000 => match opcode {
+                0x00E0 => "clear".to_string(),
+                0x00EE => "return".to_string(),
+                _ => raw(),
+            },
+            0x1000 => format!("jump {}", self.reference(nnn)),
+            0x2000 => match self.labels.get(&nnn) {
+                Some(name) => name.clone(),
+                None => format!(":call 0x{:03X}", nnn),
+            },
+            0x6000 => format!("v{:x} := 0x{:02X}", x, kk),
+            0x7000 => format!("v{:x} += 0x{:02X}", x, kk),
+            0x8000 => {
+                let operator = match opcode & 0x000F {
+                    0x0 => ":=",
+                    0x1 => "|=",
+                    0x2 => "&=",
+                    0x3 => "^=",
+                    0x4 => "+=",
+                    0x5 => "-=",
+                    0x6 => ">>=",
+                    0x7 => "=-",
+                    0xE => "<<=",
+                    _ => return raw(),
+                };
+
+                format!("v{:x} {} v{:x}", x, operator, y)
+            }
+            0xA000 => format!("i := {}", self.reference(nnn)),
+            0xB000 => format!("jump0 {}", self.reference(nnn)),
+            0xC000 => format!("v{:x} := random 0x{:02X}", x, kk),
+            0xD000 => format!("sprite v{:x} v{:x} {}", x, y, dec_nibble!(opcode)),
+            0xF000 => match opcode & 0x00FF {
+                0x07 => format!("v{:x} := delay", x),
+                0x0A => format!("v{:x} := key", x),
+                0x15 => format!("delay := v{:x}", x),
+                0x18 => format!("buzzer := v{:x}", x),
+                0x1E => format!("i += v{:x}", x),
+                0x29 => format!("i := hex v{:x}", x),
+                0x33 => format!("bcd v{:x}", x),
+                0x55 => format!("save v{:x}", x),
+                0x65 => format!("load v{:x}", x),
+                _ => raw(),
+            },
+            _ => raw(),
+        }
+    }
+
+    fn reference(&self, addr: u16) -> String {
+        match self.labels.get(&addr) {
+            Some(name) => name.clone(),
+            None => format!("0x{:03X}", addr),
+        }
+    }
+
+    fn is_boundary(&self, addr: u16) -> bool {
+        addr == self.end_addr || self.items.iter().any(|item| item.addr() == addr)
+    }
+
+    fn is_consumed(&self, addr: u16) -> bool {
+        self.consumed_by(addr).is_some()
+    }
+
+    fn consumed_by(&self, addr: u16) -> Option<&Construct> {
+        self.constructs.iter().find(|c| c.consumed.contains(&addr))
+    }
+}
+
+// The address an instruction jumps to, calls or points I to.
+fn target(opcode: u16) -> Option<u16> {
+    match opcode & 0xF000 {
+        0x1000 | 0x2000 | 0xA000 | 0xB000 => Some(dec_addr!(opcode)),
+        _ => None,
+    }
+}
+
+// When a skip is taken, in Octo's terms.
+fn skip_condition(opcode: u16) -> Option<String> {
+    let x = dec_x!(opcode);
+    let y = dec_y!(opcode);
+    let kk = dec_byte!(opcode);
+
+    Some(match opcode & 0xF000 {
+        0x3000 => format!("v{:x} == 0x{:02X}", x, kk),
+        0x4000 => format!("v{:x} != 0x{:02X}", x, kk),
+        0x5000 if opcode & 0x000F == 0 => format!("v{:x} == v{:x}", x, y),
+        0x9000 if opcode & 0x000F == 0 => format!("v{:x} != v{:x}", x, y),
+        0xE000 => match opcode & 0x00FF {
+            0x9E => format!("v{:x} key", x),
+            0xA1 => format!("v{:x} -key", x),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+fn negate(condition: &str) -> String {
+    if let Some(reg) = condition.strip_suffix(" -key") {
+        format!("{} key", reg)
+    } else if let Some(reg) = condition.strip_suffix(" key") {
+        format!("{} -key", reg)
+    } else if condition.contains("==") {
+        condition.replace("==", "!=")
+    } else {
+        condition.replace("!=", "==")
+    }
+}
+
+fn raw_bytes(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("0x{:02X}", b)).collect();
+    bytes.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::decompile;
+    use crate::symbols::Symbols;
+
+    // Enough of Octo to assemble what the decompiler emits.
+    fn assemble(source: &str) -> Vec<u8> {
+        let mut tokens = source.split_whitespace().peekable();
+        let mut rom: Vec<u8> = Vec::new();
+        let mut labels = HashMap::new();
+        let mut fixups = Vec::new();
+        let mut blocks = Vec::new();
+
+        let addr = |rom: &Vec<u8>| 0x200 + rom.len() as u16;
+        let reg = |token: &str| u16::from_str_radix(&token[1..], 16).unwrap();
+        let num = |token: &str| u16::from_str_radix(&token[2..], 16).unwrap();
+
+        let emit = |rom: &mut Vec<u8>, opcode: u16| rom.extend(opcode.to_be_bytes());
+
+        while let Some(token) = tokens.next() {
+            let mut operand = |rom: &mut Vec<u8>, base: u16, token: &str| {
+                if token.starts_with("0x") {
+                    emit(rom, base | num(token));
+                } else {
+                    fixups.push((rom.len(), base, token.to_string()));
+                    emit(rom, base);
+                }
+            };
+
+            match token {
+                ":" => {
+                    labels.insert(tokens.next().unwrap().to_string(), addr(&rom));
+                }
+                "if" => {
+                    let x = reg(tokens.next().unwrap()) << 8;
+                    let mut op = tokens.next().unwrap();
+                    let rhs = match op {
+                        "key" | "-key" => "",
+                        _ => tokens.next().unwrap(),
+                    };
+
+                    let is_block = tokens.next().unwrap() == "begin";
+
+                    // Then skips when the condition is false.
+                    if !is_block {
+                        op = match op {
+                            "==" => "!=",
+                            "!=" => "==",
+                            "key" => "-key",
+                            _ => "key",
+                        };
+                    }
+
+                    let opcode = match (op, rhs.starts_with('v')) {
+                        ("key", _) => 0xE09E | x,
+                        ("-key", _) => 0xE0A1 | x,
+                        ("==", true) => 0x5000 | x | reg(rhs) << 4,
+                        ("!=", true) => 0x9000 | x | reg(rhs) << 4,
+                        ("==", false) => 0x3000 | x | num(rhs),
+                        _ => 0x4000 | x | num(rhs),
+                    };
+
+                    emit(&mut rom, opcode);
+
+                    if is_block {
+                        blocks.push(rom.len());
+                        emit(&mut rom, 0x1000);
+                    }
+                }
+                "else" => {
+                    let begin = blocks.pop().unwrap();
+                    blocks.push(rom.len());
+                    emit(&mut rom, 0x1000);
+
+                    let to = addr(&rom).to_be_bytes();
+                    rom[begin] |= to[0];
+                    rom[begin + 1] = to[1];
+                }
+                "end" => {
+                    let jump = blocks.pop().unwrap();
+                    let to = addr(&rom).to_be_bytes();
+                    rom[jump] |= to[0];
+                    rom[jump + 1] = to[1];
+                }
+                "loop" => blocks.push(rom.len()),
+                "again" => {
+                    let start = blocks.pop().unwrap();
+                    emit(&mut rom, 0x1000 | (0x200 + start as u16));
+                }
+                "clear" => emit(&mut rom, 0x00E0),
+                "return" => emit(&mut rom, 0x00EE),
+                "jump" => operand(&mut rom, 0x1000, tokens.next().unwrap()),
+                "jump0" => operand(&mut rom, 0xB000, tokens.next().unwrap()),
+                ":call" => operand(&mut rom, 0x2000, tokens.next().unwrap()),
+                "sprite" => {
+                    let x = reg(tokens.next().unwrap());
+                    let y = reg(tokens.next().unwrap());
+                    let n: u16 = tokens.next().unwrap().parse().unwrap();
+                    emit(&mut rom, 0xD000 | x << 8 | y << 4 | n);
+                }
+                "bcd" => emit(&mut rom, 0xF033 | reg(tokens.next().unwrap()) << 8),
+                "save" => emit(&mut rom, 0xF055 | reg(tokens.next().unwrap()) << 8),
+                "load" => emit(&mut rom, 0xF065 | reg(tokens.next().unwrap()) << 8),
+                "delay" | "buzzer" => {
+                    tokens.next();
+                    let x = reg(tokens.next().unwrap()) << 8;
+                    emit(&mut rom, if token == "delay" { 0xF015 } else { 0xF018 } | x);
+                }
+                "i" => match (tokens.next().unwrap(), tokens.next().unwrap()) {
+                    ("+=", vx) => emit(&mut rom, 0xF01E | reg(vx) << 8),
+                    (_, "hex") => emit(&mut rom, 0xF029 | reg(tokens.next().unwrap()) << 8),
+                    (_, value) => operand(&mut rom, 0xA000, value),
+                },
+                _ if token.starts_with("0x") => rom.push(num(token) as u8),
+                _ if token.len() == 2 && token.starts_with('v') => {
+                    let x = reg(token) << 8;
+                    let op = tokens.next().unwrap();
+                    let value = tokens.next().unwrap();
+
+                    let opcode = match (op, value) {
+                        (":=", "delay") => 0xF007 | x,
+                        (":=", "key") => 0xF00A | x,
+                        (":=", "random") => 0xC000 | x | num(tokens.next().unwrap()),
+                        (":=", _) if value.starts_with("0x") => 0x6000 | x | num(value),
+                        ("+=", _) if value.starts_with("0x") => 0x7000 | x | num(value),
+                        _ => {
+                            let n = [":=", "|=", "&=", "^=", "+=", "-=", ">>=", "=-"]
+                                .iter()
+                                .position(|&o| o == op)
+                                .unwrap_or(0xE) as u16;
+                            0x8000 | x | reg(value) << 4 | n
+                        }
+                    };
+
+                    emit(&mut rom, opcode);
+                }
+                name => operand(&mut rom, 0x2000, name),
+            }
+        }
+
+        for (offset, base, name) in fixups {
+            let [hi, lo] = (base | labels[&name]).to_be_bytes();
+            rom[offset] = hi;
+            rom[offset + 1] = lo;
+        }
+
+        rom
+    }
+
+    #[test]
+    fn test_decompile() {
+        let rom = [
+            0x00, 0xE0, // CLS
+            0xA2, 0x14, // LD I, 0x214
+            0xD0, 0x15, // DRW V0, V1, 5
+            0x30, 0x05, // SE V0, 5
+            0x12, 0x0E, // JP 0x20E
+            0x70, 0x01, // ADD V0, 1
+            0x22, 0x12, // CALL 0x212
+            0x12, 0x04, // JP 0x204
+            0x12, 0x10, // Unreachable
+            0x00, 0xEE, // RET
+            0xF0, 0x90, 0x90, 0x90, 0xF0, // Sprite
+        ];
+
+        let source = decompile(&rom, 0x200, &Symbols::default());
+
+        assert_eq!(
+            source,
+            "\
+: main
+\tclear
+\ti := sprite_214
+\tloop
+\t\tsprite v0 v1 5
+\t\tif v0 == 0x05 begin
+\t\t\tv0 += 0x01
+\t\t\tsub_212
+\t\tend
+\tagain
+\t0x12 0x10
+: sub_212
+\treturn
+: sprite_214
+\t0xF0 0x90 0x90 0x90 0xF0
+"
+        );
+        assert_eq!(assemble(&source), rom);
+
+        let symbols = Symbols::parse("start 0x200\nbounce 0x212\nball 0x214").unwrap();
+        let source = decompile(&rom, 0x200, &symbols);
+        assert!(source.starts_with(": main\n\tclear\n\ti := ball\n"));
+        assert!(source.contains("\t\t\tbounce\n"));
+        assert!(source.contains(": bounce\n\treturn\n: ball\n"));
+        assert_eq!(assemble(&source), rom);
+
+        // Random roms, heavy on jumps and skips, assemble back to the same
+        // bytes.
+        for seed in 0..500 {
+            let mut rng = oorandom::Rand32::new(seed);
+            let len = rng.rand_range(2..64) as u16;
+            let mut rom = Vec::new();
+
+            while rom.len() < len as usize * 2 {
+                let to = 0x200 + rng.rand_range(0..len as u32 * 2 + 1) as u16;
+                let random = rng.rand_u32() as u16;
+                let x = random & 0x0F00;
+
+                let opcodes: Vec<u16> = match rng.rand_range(0..6) {
+                    0 => vec![random],
+                    1 => vec![[0x1000, 0x2000, 0xA000, 0xB000][random as usize % 4] | to & !1],
+                    2 => vec![
+                        [0x3000 | random & 0xFFF, 0x9000 | random & 0xFF0, 0xE09E | x]
+                            [random as usize % 3],
+                    ],
+                    3 => vec![0x4000 | random & 0xFFF, 0x1000 | to & !1],
+                    4 => vec![
+                        [0x00E0, 0x00EE, 0xD000 | random & 0xFFF, 0xF065 | x][random as usize % 4],
+                    ],
+                    _ => vec![0x1000 | to.saturating_sub(rng.rand_range(0..16) as u16)],
+                };
+
+                for opcode in opcodes {
+                    rom.extend(opcode.to_be_bytes());
+                }
+            }
+
+            // Odd sizes end in a lone byte.
+            if seed % 3 == 0 {
+                rom.push(rng.rand_u32() as u8);
+            }
+
+            let source = decompile(&rom, 0x200, &Symbols::default());
+            assert_eq!(assemble(&source), rom, "seed {}\n{}", seed, source);
+        }
+    }
+}
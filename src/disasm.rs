@@ -0,0 +1,123 @@
+use crate::{dec_addr, dec_byte, dec_nibble, dec_x, dec_y, symbols::Symbols};
+
+// Mnemonics follow Cowgod's Chip-8 technical reference, as in chip8.rs.
+// Addresses are named after the symbols, if any.
+pub fn disassemble(opcode: u16, symbols: &Symbols) -> Option<String> {
+    let x = dec_x!(opcode);
+    let y = dec_y!(opcode);
+    let kk = dec_byte!(opcode);
+    let nnn = dec_addr!(opcode);
+
+    Some(match opcode & 0xF000 {
+        0x0000 => match opcode {
+            0x00E0 => "CLS".to_string(),
+            0x00EE => "RET".to_string(),
+            _ => format!("SYS {}", symbols.format(nnn)),
+        },
+        0x1000 => format!("JP {}", symbols.format(nnn)),
+        0x2000 => format!("CALL {}", symbols.format(nnn)),
+        0x3000 => format!("SE V{:X}, 0x{:02X}", x, kk),
+        0x4000 => format!("SNE V{:X}, 0x{:02X}", x, kk),
+        0x5000 if opcode & 0x000F == 0 => format!("SE V{:X}, V{:X}", x, y),
+        0x6000 => format!("LD V{:X}, 0x{:02X}", x, kk),
+        0x7000 => format!("ADD V{:X}, 0x{:02X}", x, kk),
+        0x8000 => {
+            let mnemonic = match opcode & 0x000F {
+                0x0000 => "LD",
+                0x0001 => "OR",
+                0x0002 => "AND",
+                0x0003 => "XOR",
+                0x0004 => "ADD",
+                0x0005 => "SUB",
+                0x0006 => "SHR",
+                0x0007 => "SUBN",
+                0x000E => "SHL",
+                _ => return None,
+            };
+
+            format!("{} V{:X}, V{:X}", mnemonic, x, y)
+        }
+        0x9000 if opcode & 0x000F == 0 => format!("SNE V{:X}, V{:X}", x, y),
+        0xA000 => format!("LD I, {}", symbols.format(nnn)),
+        0xB000 => format!("JP V0, {}", symbols.format(nnn)),
+        0xC000 => format!("RND V{:X}, 0x{:02X}", x, kk),
+        0xD000 => format!("DRW V{:X}, V{:X}, {}", x, y, dec_nibble!(opcode)),
+        0xE000 => match opcode & 0x00FF {
+            0x009E => format!("SKP V{:X}", x),
+            0x00A1 => format!("SKNP V{:X}", x),
+            _ => return None,
+        },
+        0xF000 => match opcode & 0x00FF {
+            0x0007 => format!("LD V{:X}, DT", x),
+            0x000A => format!("LD V{:X}, K", x),
+            0x0015 => format!("LD DT, V{:X}", x),
+            0x0018 => format!("LD ST, V{:X}", x),
+            0x001E => format!("ADD I, V{:X}", x),
+            0x0029 => format!("LD F, V{:X}", x),
+            0x0033 => format!("LD B, V{:X}", x),
+            0x0055 => format!("LD [I], V{:X}", x),
+            0x0065 => format!("LD V{:X}, [I]", x),
+            _ => return None,
+        },
+        _ => return None,
+    })
+}
+
+// One line per word: address, opcode and mnemonic. Words that are not valid
+// instructions (usually sprite data) are shown as DW. Named addresses get a
+// label line.
+pub fn listing(rom: &[u8], start_addr: u16, symbols: &Symbols) -> Vec<String> {
+    rom.chunks(2)
+        .enumerate()
+        .flat_map(|(i, bytes)| {
+            let addr = start_addr as usize + i * 2;
+            let label = symbols.name(addr as u16).map(|name| format!("{}:", name));
+
+            let line = match *bytes {
+                [hi, lo] => {
+                    let opcode = (hi as u16) << 8 | lo as u16;
+                    let text =
+                        disassemble(opcode, symbols).unwrap_or(format!("DW 0x{:04X}", opcode));
+
+                    format!("0x{:03X}  {:04X}  {}", addr, opcode, text)
+                }
+                _ => format!("0x{:03X}  {:02X}    DB 0x{:02X}", addr, bytes[0], bytes[0]),
+            };
+
+            label.into_iter().chain([line])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, listing};
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn test_disassemble() {
+        let none = Symbols::default();
+
+        assert_eq!(disassemble(0x00E0, &none).unwrap(), "CLS");
+        assert_eq!(disassemble(0x2ABC, &none).unwrap(), "CALL 0xABC");
+        assert_eq!(disassemble(0x8A1E, &none).unwrap(), "SHL VA, V1");
+        assert_eq!(disassemble(0xD125, &none).unwrap(), "DRW V1, V2, 5");
+        assert_eq!(disassemble(0xF365, &none).unwrap(), "LD V3, [I]");
+        assert_eq!(disassemble(0x5121, &none), None);
+        assert_eq!(disassemble(0xE1FF, &none), None);
+
+        let symbols = Symbols::parse("sprite 0x202").unwrap();
+
+        assert_eq!(disassemble(0xA203, &symbols).unwrap(), "LD I, sprite+0x1");
+        assert_eq!(
+            listing(&[0x60, 0x0A, 0xFF, 0xFF, 0x12, 0x02, 0x3C], 0x200, &symbols),
+            [
+                "0x200  600A  LD V0, 0x0A",
+                "sprite:",
+                "0x202  FFFF  DW 0xFFFF",
+                "0x204  1202  JP sprite",
+                "0x206  3C    DB 0x3C"
+            ]
+        );
+    }
+}
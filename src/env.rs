@@ -0,0 +1,262 @@
+use std::{sync::Arc, thread};
+
+use crate::chip8::{Chip8, Quirks};
+
+const NUM_KEYS: usize = 16;
+
+// What agents see of the machine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Observation {
+    // One byte per pixel, row by row: 0 or 1.
+    Screen,
+    // The whole memory.
+    Ram,
+}
+
+// Rewards the change from the memory before a frame to the memory after it,
+// e.g. a score going up.
+pub type RewardFn = Arc<dyn Fn(&[u8], &[u8]) -> f32 + Send + Sync>;
+
+// Tells from the memory whether the episode is over, e.g. no lives left.
+// Episodes also end when the rom halts on a fault.
+pub type DoneFn = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub observation: Vec<u8>,
+    pub reward: f32,
+    pub done: bool,
+}
+
+// A rom as a reinforcement learning environment. Actions are keypad bit
+// masks, bit n holding key n down, and last for the frames of a step.
+#[derive(Clone)]
+pub struct Env {
+    chip8: Chip8,
+    rom: Vec<u8>,
+    quirks: Quirks,
+    ips: u32,
+    observation: Observation,
+    reward: RewardFn,
+    done: DoneFn,
+    is_done: bool,
+}
+
+impl Env {
+    // Without reward and done functions, every frame rewards 0 and episodes
+    // never end.
+    pub fn init(
+        rom: &[u8],
+        quirks: Quirks,
+        ips: u32,
+        observation: Observation,
+    ) -> Result<Self, String> {
+        let mut chip8 = Chip8::init(0);
+        chip8.load(rom)?;
+
+        let mut env = Self {
+            chip8,
+            rom: rom.to_vec(),
+            quirks,
+            ips,
+            observation,
+            reward: Arc::new(|_, _| 0.0),
+            done: Arc::new(|_| false),
+            is_done: false,
+        };
+        env.reset(0);
+
+        Ok(env)
+    }
+
+    pub fn with_reward<F>(mut self, reward: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> f32 + Send + Sync + 'static,
+    {
+        self.reward = Arc::new(reward);
+        self
+    }
+
+    pub fn with_done<F>(mut self, done: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.done = Arc::new(done);
+        self
+    }
+
+    // Starts a new episode, with the random number generator seeded.
+    pub fn reset(&mut self, seed: u64) -> Vec<u8> {
+        self.chip8 = Chip8::init(seed);
+        self.chip8.set_quirks(self.quirks);
+        self.chip8
+            .load(&self.rom)
+            .expect("Env::init checked that the rom fits.");
+        self.is_done = false;
+
+        self.observation()
+    }
+
+    // Runs frame_skip frames with the action's keys held, adding up their
+    // rewards. Stops early when the episode ends; after that, steps do
+    // nothing until the next reset.
+    pub fn step(&mut self, action: u16, frame_skip: u32) -> Step {
+        let keys: [bool; NUM_KEYS] = std::array::from_fn(|i| action & (1 << i) != 0);
+        let mut reward = 0.0;
+
+        self.chip8.write_keys(&keys);
+
+        for _ in 0..frame_skip {
+            if self.is_done {
+                break;
+            }
+
+            let mem = self.chip8.mem().to_vec();
+            self.chip8.run_frame(self.ips);
+
+            reward += (self.reward)(&mem, self.chip8.mem());
+            self.is_done = (self.done)(self.chip8.mem()) || self.chip8.fault().is_some();
+        }
+
+        Step {
+            observation: self.observation(),
+            reward,
+            done: self.is_done,
+        }
+    }
+
+    pub fn observation(&self) -> Vec<u8> {
+        match self.observation {
+            Observation::Screen => self.chip8.video().iter().map(|&p| p.min(1)).collect(),
+            Observation::Ram => self.chip8.mem().to_vec(),
+        }
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+}
+
+// Copies of an environment stepped in parallel, one thread per CPU.
+pub struct VecEnv {
+    envs: Vec<Env>,
+}
+
+impl VecEnv {
+    pub fn init(env: &Env, num_envs: usize) -> Self {
+        Self {
+            envs: vec![env.clone(); num_envs],
+        }
+    }
+
+    pub fn envs(&mut self) -> &mut [Env] {
+        &mut self.envs
+    }
+
+    // Environment n is seeded with seed + n.
+    pub fn reset(&mut self, seed: u64) -> Vec<Vec<u8>> {
+        self.envs
+            .iter_mut()
+            .enumerate()
+            .map(|(n, env)| env.reset(seed.wrapping_add(n as u64)))
+            .collect()
+    }
+
+    // One action per environment.
+    pub fn step(&mut self, actions: &[u16], frame_skip: u32) -> Vec<Step> {
+        assert_eq!(
+            actions.len(),
+            self.envs.len(),
+            "One action per environment."
+        );
+
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = self.envs.len().div_ceil(threads).max(1);
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = self
+                .envs
+                .chunks_mut(chunk_size)
+                .zip(actions.chunks(chunk_size))
+                .map(|(envs, actions)| {
+                    scope.spawn(move || {
+                        envs.iter_mut()
+                            .zip(actions)
+                            .map(|(env, &action)| env.step(action, frame_skip))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Env, Observation, VecEnv};
+    use crate::chip8::Quirks;
+
+    // Adds 1 to the byte at 0x300 every frame, or 2 while key 5 is held.
+    const COUNTER: [u8; 28] = [
+        0xA3, 0x00, // LD I, 0x300
+        0xF0, 0x65, // LD V0, [I]
+        0x70, 0x01, // ADD V0, 1
+        0x62, 0x05, // LD V2, 5
+        0xE2, 0xA1, // SKNP V2
+        0x70, 0x01, // ADD V0, 1
+        0xA3, 0x00, // LD I, 0x300
+        0xF0, 0x55, // LD [I], V0
+        0x61, 0x01, // LD V1, 1
+        0xF1, 0x15, // LD DT, V1
+        0xF1, 0x07, // LD V1, DT
+        0x31, 0x00, // SE V1, 0
+        0x12, 0x14, // JP 0x214
+        0x12, 0x00, // JP 0x200
+    ];
+
+    #[test]
+    fn test_env() {
+        let env = Env::init(&COUNTER, Quirks::DEFAULT, 1200, Observation::Ram)
+            .unwrap()
+            .with_reward(|before, after| after[0x300] as f32 - before[0x300] as f32)
+            .with_done(|mem| mem[0x300] >= 20);
+
+        let mut env1 = env.clone();
+        assert_eq!(env1.reset(1)[0x200..0x202], [0xA3, 0x00]);
+
+        let step = env1.step(0, 4);
+        assert_eq!(step.observation[0x300], 4);
+        assert_eq!(step.reward, 4.0);
+        assert!(!step.done);
+
+        let step = env1.step(1 << 5, 4);
+        assert_eq!(step.reward, 8.0);
+
+        // Clones carry on from the same state.
+        let mut env2 = env1.clone();
+        assert_eq!(env1.step(0, 1), env2.step(0, 1));
+
+        let step = env1.step(0, 100);
+        assert!(step.done);
+        assert_eq!(step.observation[0x300], 20);
+        assert_eq!(env1.step(0, 1).reward, 0.0);
+
+        let mut envs = VecEnv::init(&env, 3);
+        envs.reset(7);
+        let steps = envs.step(&[0, 1 << 5, 0], 2);
+        assert_eq!(steps.len(), 3);
+        assert_eq!(steps[0], steps[2]);
+        assert_eq!(steps[1].reward, 2.0 * steps[0].reward);
+        assert_eq!(envs.envs()[1].chip8().rng_seed(), 8);
+
+        let mut screen = Env::init(&COUNTER, Quirks::DEFAULT, 1200, Observation::Screen).unwrap();
+        assert_eq!(screen.reset(0).len(), 64 * 32);
+
+        assert!(Env::init(&[0; 4096], Quirks::DEFAULT, 1200, Observation::Ram).is_err());
+    }
+}
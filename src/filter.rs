@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+
+// Display filters against sprite flicker. They only change what is shown,
+// never the emulation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    None,
+    // Phosphor persistence: unlit pixels keep this fraction (0..1) of their
+    // brightness on each frame.
+    Decay(f32),
+    // A pixel stays lit while it was lit in any of the last N frames.
+    Max(usize),
+    // Frames that only erase pixels are held back for up to N frames, so an
+    // erase is shown together with the redraw that follows it.
+    VBlank(usize),
+}
+
+impl Filter {
+    // Parses "none", "decay:0.6", "max:3" or "vblank:2".
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (name, strength) = spec.split_once(':').unwrap_or((spec, ""));
+        let invalid = || format!("Invalid filter strength: {}", spec);
+
+        Ok(match name {
+            "none" => Filter::None,
+            "decay" => match strength.parse() {
+                Ok(s) if (0.0..1.0).contains(&s) => Filter::Decay(s),
+                _ if strength.is_empty() => Filter::Decay(0.6),
+                _ => return Err(invalid()),
+            },
+            "max" => Filter::Max(if strength.is_empty() {
+                2
+            } else {
+                strength.parse().map_err(|_| invalid())?
+            }),
+            "vblank" => Filter::VBlank(if strength.is_empty() {
+                1
+            } else {
+                strength.parse().map_err(|_| invalid())?
+            }),
+            _ => return Err(format!("Unknown filter: {}", name)),
+        })
+    }
+}
+
+// A displayed pixel: palette index and brightness in 0..1.
+pub type Pixel = (u8, f32);
+
+pub struct FrameFilter {
+    filter: Filter,
+    shown: Vec<Pixel>,
+    history: VecDeque<Vec<u8>>,
+    held: usize,
+}
+
+impl FrameFilter {
+    pub fn init(filter: Filter, size: usize) -> Self {
+        Self {
+            filter,
+            shown: vec![(0, 0.0); size],
+            history: VecDeque::new(),
+            held: 0,
+        }
+    }
+
+    pub fn apply(&mut self, chip8_video: &[u8]) -> &[Pixel] {
+        match self.filter {
+            Filter::None => self.show(chip8_video),
+            Filter::Decay(strength) => {
+                for (shown, &pixel) in self.shown.iter_mut().zip(chip8_video) {
+                    *shown = if pixel != 0 {
+                        (pixel, 1.0)
+                    } else if shown.1 * strength >= 0.05 {
+                        (shown.0, shown.1 * strength)
+                    } else {
+                        (0, 0.0)
+                    };
+                }
+            }
+            Filter::Max(frames) => {
+                self.history.push_front(chip8_video.to_vec());
+                self.history.truncate(frames.max(1));
+
+                for (i, shown) in self.shown.iter_mut().enumerate() {
+                    // The most recent lit value wins.
+                    let pixel = self
+                        .history
+                        .iter()
+                        .map(|video| video[i])
+                        .find(|&pixel| pixel != 0)
+                        .unwrap_or(0);
+
+                    *shown = (pixel, if pixel != 0 { 1.0 } else { 0.0 });
+                }
+            }
+            Filter::VBlank(frames) => {
+                let erase_only = self
+                    .shown
+                    .iter()
+                    .zip(chip8_video)
+                    .all(|(&(shown, _), &pixel)| pixel == 0 || pixel == shown);
+
+                if erase_only && self.held < frames {
+                    self.held += 1;
+                } else {
+                    self.held = 0;
+                    self.show(chip8_video);
+                }
+            }
+        }
+
+        &self.shown
+    }
+
+    fn show(&mut self, chip8_video: &[u8]) {
+        for (shown, &pixel) in self.shown.iter_mut().zip(chip8_video) {
+            *shown = (pixel, if pixel != 0 { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, FrameFilter};
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(Filter::parse("none"), Ok(Filter::None));
+        assert_eq!(Filter::parse("decay:0.5"), Ok(Filter::Decay(0.5)));
+        assert_eq!(Filter::parse("max"), Ok(Filter::Max(2)));
+        assert_eq!(Filter::parse("vblank:3"), Ok(Filter::VBlank(3)));
+        assert!(Filter::parse("decay:2").is_err());
+        assert!(Filter::parse("blur").is_err());
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut filter = FrameFilter::init(Filter::Decay(0.5), 2);
+
+        assert_eq!(filter.apply(&[1, 0]), [(1, 1.0), (0, 0.0)]);
+        assert_eq!(filter.apply(&[0, 1]), [(1, 0.5), (1, 1.0)]);
+        assert_eq!(filter.apply(&[0, 0]), [(1, 0.25), (1, 0.5)]);
+    }
+
+    #[test]
+    fn test_max() {
+        let mut filter = FrameFilter::init(Filter::Max(2), 2);
+
+        filter.apply(&[1, 0]);
+        assert_eq!(filter.apply(&[0, 2]), [(1, 1.0), (2, 1.0)]);
+        assert_eq!(filter.apply(&[0, 0]), [(0, 0.0), (2, 1.0)]);
+    }
+
+    #[test]
+    fn test_vblank() {
+        let mut filter = FrameFilter::init(Filter::VBlank(1), 2);
+
+        filter.apply(&[1, 0]);
+        // Erased sprite: held until it is redrawn.
+        assert_eq!(filter.apply(&[0, 0]), [(1, 1.0), (0, 0.0)]);
+        assert_eq!(filter.apply(&[0, 1]), [(0, 0.0), (1, 1.0)]);
+        // Not held for longer than the strength.
+        assert_eq!(filter.apply(&[0, 0]), [(0, 0.0), (1, 1.0)]);
+        assert_eq!(filter.apply(&[0, 0]), [(0, 0.0), (0, 0.0)]);
+    }
+}
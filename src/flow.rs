@@ -0,0 +1,558 @@
+// Static control flow analysis of roms: basic blocks, subroutines, sprite
+// data and unreachable bytes, with a Graphviz export.
+//
+//     lrchip8 cfg pong.ch8 > pong.dot
+//     dot -Tsvg pong.dot > pong.svg
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+    ops::Range,
+};
+
+use crate::{dec_addr, dec_nibble, dec_x, disasm, symbols::Symbols};
+
+// Jump tables hold at most 256 bytes, the range of V0.
+const MAX_JUMP_TABLE: u16 = 0x100;
+
+// Dxy0 draws 16x16 sprites on SCHIP.
+const BIG_SPRITE_LEN: u16 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EdgeKind {
+    // The next instruction.
+    Next,
+    Jump,
+    // The instruction after the next, when a skip is taken.
+    Skip,
+    Call,
+    // A guess at where a Bnnn jump goes.
+    Indirect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edge {
+    pub to: u16,
+    pub kind: EdgeKind,
+}
+
+// Instructions from start up to end, exclusive, run one after the other.
+// Blocks end on jumps, skips, calls and returns, and before instructions
+// that are jumped to.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub start: u16,
+    pub end: u16,
+    pub edges: Vec<Edge>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DataKind {
+    // Drawn with Dxyn.
+    Sprite,
+    // Read or written with Fx33, Fx55 or Fx65.
+    Data,
+}
+
+// Memory pointed to by an Annn and used before I changes again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DataRef {
+    pub addr: u16,
+    pub len: u16,
+    pub kind: DataKind,
+}
+
+pub struct Flow {
+    rom: Vec<u8>,
+    start_addr: u16,
+    blocks: BTreeMap<u16, Block>,
+    subroutines: BTreeSet<u16>,
+    data: Vec<DataRef>,
+}
+
+impl Flow {
+    // Follows every path from the first instruction of the rom. Bnnn jumps
+    // are assumed to go into a table of jumps at nnn. The rom must fit in
+    // memory, as with Chip8::load.
+    pub fn analyze(rom: &[u8], start_addr: u16) -> Self {
+        let mut flow = Self {
+            rom: rom.to_vec(),
+            start_addr,
+            blocks: BTreeMap::new(),
+            subroutines: BTreeSet::new(),
+            data: Vec::new(),
+        };
+
+        let mut code = BTreeSet::new();
+        let mut leaders = BTreeSet::from([start_addr]);
+        let mut pending = vec![start_addr];
+
+        while let Some(addr) = pending.pop() {
+            let Some(opcode) = flow.opcode(addr) else {
+                continue;
+            };
+
+            if !code.insert(addr) {
+                continue;
+            }
+
+            let (edges, ends_block) = flow.successors(addr, opcode);
+
+            for edge in edges {
+                if ends_block {
+                    leaders.insert(edge.to);
+                }
+
+                if edge.kind == EdgeKind::Call {
+                    flow.subroutines.insert(edge.to);
+                }
+
+                pending.push(edge.to);
+            }
+        }
+
+        for &start in leaders.iter().filter(|addr| code.contains(addr)) {
+            let mut addr = start;
+
+            let edges = loop {
+                let (edges, ends_block) = flow.successors(addr, flow.opcode(addr).unwrap());
+                addr += 2;
+
+                if ends_block {
+                    break edges;
+                }
+
+                if !code.contains(&addr) || leaders.contains(&addr) {
+                    break vec![Edge {
+                        to: addr,
+                        kind: EdgeKind::Next,
+                    }];
+                }
+            };
+
+            flow.blocks.insert(
+                start,
+                Block {
+                    start,
+                    end: addr,
+                    edges,
+                },
+            );
+        }
+
+        flow.data = flow.find_data();
+        flow
+    }
+
+    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+        self.blocks.values()
+    }
+
+    // Addresses called with 2nnn.
+    pub fn subroutines(&self) -> &BTreeSet<u16> {
+        &self.subroutines
+    }
+
+    // Sorted by address.
+    pub fn data(&self) -> &[DataRef] {
+        &self.data
+    }
+
+    // Bytes of the rom that are neither code nor referenced data, as address
+    // ranges.
+    pub fn unreachable(&self) -> Vec<Range<u16>> {
+        let end_addr = self.start_addr + self.rom.len() as u16;
+        let mut used = vec![false; self.rom.len()];
+
+        let code = self.blocks().map(|block| (block.start, block.end));
+        let data = self
+            .data
+            .iter()
+            .map(|data| (data.addr, data.addr + data.len));
+
+        for (start, end) in code.chain(data) {
+            for addr in start.max(self.start_addr)..end.min(end_addr) {
+                used[(addr - self.start_addr) as usize] = true;
+            }
+        }
+
+        let mut ranges: Vec<Range<u16>> = Vec::new();
+
+        for (offset, _) in used.iter().enumerate().filter(|(_, &used)| !used) {
+            let addr = self.start_addr + offset as u16;
+
+            match ranges.last_mut() {
+                Some(range) if range.end == addr => range.end += 1,
+                _ => ranges.push(addr..addr + 1),
+            }
+        }
+
+        ranges
+    }
+
+    // A Graphviz digraph with the main program and each subroutine in its own
+    // cluster. Sprite data and unreachable bytes are listed in comments.
+    // Addresses are named after the symbols, if any.
+    pub fn to_dot(&self, symbols: &Symbols) -> String {
+        let mut dot = String::new();
+
+        for data in &self.data {
+            let kind = match data.kind {
+                DataKind::Sprite => "Sprite",
+                DataKind::Data => "Data",
+            };
+
+            writeln!(
+                dot,
+                "// {} at {}, {} bytes",
+                kind,
+                symbols.format(data.addr),
+                data.len
+            )
+            .unwrap();
+        }
+
+        for range in self.unreachable() {
+            writeln!(
+                dot,
+                "// Unreachable: {}-{}, {} bytes",
+                symbols.format(range.start),
+                symbols.format(range.end - 1),
+                range.len()
+            )
+            .unwrap();
+        }
+
+        dot.push_str("digraph rom {\n");
+        dot.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        for (entry, blocks) in self.clusters() {
+            let name = match symbols.name(entry) {
+                Some(name) => name.to_string(),
+                None if entry == self.start_addr => "main".to_string(),
+                None => format!("sub_{:03X}", entry),
+            };
+
+            writeln!(dot, "    subgraph cluster_{:03X} {{", entry).unwrap();
+            writeln!(dot, "        label=\"{}\";", name).unwrap();
+
+            for block in blocks {
+                writeln!(
+                    dot,
+                    "        b{:03X} [label=\"{}\"];",
+                    block.start,
+                    self.block_label(block, symbols)
+                )
+                .unwrap();
+            }
+
+            dot.push_str("    }\n");
+        }
+
+        let mut outside = BTreeSet::new();
+
+        for block in self.blocks() {
+            for edge in &block.edges {
+                let style = match edge.kind {
+                    EdgeKind::Next => "",
+                    EdgeKind::Jump => " [label=\"jump\"]",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Call => " [label=\"call\", style=dashed]",
+                    EdgeKind::Indirect => " [label=\"jump table\", style=dotted]",
+                };
+
+                if !self.blocks.contains_key(&edge.to) {
+                    outside.insert(edge.to);
+                }
+
+                writeln!(
+                    dot,
+                    "    b{:03X} -> b{:03X}{};",
+                    block.start, edge.to, style
+                )
+                .unwrap();
+            }
+        }
+
+        for addr in outside {
+            writeln!(
+                dot,
+                "    b{:03X} [label=\"{}\\noutside the rom\", style=dashed];",
+                addr,
+                symbols.format(addr)
+            )
+            .unwrap();
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn opcode(&self, addr: u16) -> Option<u16> {
+        let offset = addr.checked_sub(self.start_addr)? as usize;
+        let bytes = self.rom.get(offset..offset + 2)?;
+
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    // Where the instruction at addr can go, and whether it ends a block.
+    fn successors(&self, addr: u16, opcode: u16) -> (Vec<Edge>, bool) {
+        let edge = |to, kind| Edge { to, kind };
+        let next = edge(addr + 2, EdgeKind::Next);
+        let nnn = dec_addr!(opcode);
+
+        let is_skip = match opcode & 0xF000 {
+            0x3000 | 0x4000 => true,
+            0x5000 | 0x9000 => opcode & 0x000F == 0,
+            0xE000 => matches!(opcode & 0x00FF, 0x009E | 0x00A1),
+            _ => false,
+        };
+
+        if is_skip {
+            return (vec![next, edge(addr + 4, EdgeKind::Skip)], true);
+        }
+
+        match opcode & 0xF000 {
+            _ if opcode == 0x00EE => (vec![], true),
+            0x1000 => (vec![edge(nnn, EdgeKind::Jump)], true),
+            0x2000 => (vec![edge(nnn, EdgeKind::Call), next], true),
+            0xB000 => {
+                let edges = self
+                    .jump_table(nnn)
+                    .into_iter()
+                    .map(|to| edge(to, EdgeKind::Indirect))
+                    .collect();
+
+                (edges, true)
+            }
+            // Unknown opcodes halt the rom.
+            _ if disasm::disassemble(opcode, &Symbols::default()).is_none() => (vec![], true),
+            _ => (vec![next], false),
+        }
+    }
+
+    // The jumps one after the other at addr, or addr itself if there are none.
+    fn jump_table(&self, addr: u16) -> Vec<u16> {
+        let entries: Vec<u16> = (addr..addr + MAX_JUMP_TABLE)
+            .step_by(2)
+            .take_while(|&entry| self.opcode(entry).is_some_and(|op| op & 0xF000 == 0x1000))
+            .collect();
+
+        if entries.is_empty() {
+            vec![addr]
+        } else {
+            entries
+        }
+    }
+
+    // Follows I from each Annn to the instructions that use it in the same
+    // block, or in the next one when the block just falls through to it. An
+    // address drawn and also read is reported as a sprite.
+    fn find_data(&self) -> Vec<DataRef> {
+        let mut data: BTreeMap<u16, DataRef> = BTreeMap::new();
+        let mut i = None;
+        let mut falls_through_to = None;
+
+        for block in self.blocks() {
+            if falls_through_to != Some(block.start) {
+                i = None;
+            }
+
+            falls_through_to = match block.edges[..] {
+                [Edge {
+                    to,
+                    kind: EdgeKind::Next,
+                }] => Some(to),
+                _ => None,
+            };
+
+            for addr in (block.start..block.end).step_by(2) {
+                let opcode = self.opcode(addr).unwrap();
+                let x = dec_x!(opcode) as u16;
+
+                let used = match opcode & 0xF0FF {
+                    _ if opcode & 0xF000 == 0xA000 => {
+                        i = Some(dec_addr!(opcode));
+                        continue;
+                    }
+                    _ if opcode & 0xF000 == 0xD000 => match dec_nibble!(opcode) {
+                        0 => (BIG_SPRITE_LEN, DataKind::Sprite),
+                        n => (n as u16, DataKind::Sprite),
+                    },
+                    0xF033 => (3, DataKind::Data),
+                    0xF055 | 0xF065 => (x + 1, DataKind::Data),
+                    // I moves.
+                    0xF01E | 0xF029 => {
+                        i = None;
+                        continue;
+                    }
+                    _ => continue,
+                };
+
+                let Some(addr) = i else {
+                    continue;
+                };
+
+                let (len, kind) = used;
+                let entry = data.entry(addr).or_insert(DataRef { addr, len, kind });
+                entry.len = entry.len.max(len);
+
+                if kind == DataKind::Sprite {
+                    entry.kind = kind;
+                }
+
+                // With the memory quirk, Fx55 and Fx65 move I.
+                if kind == DataKind::Data && opcode & 0x00FF != 0x0033 {
+                    i = None;
+                }
+            }
+        }
+
+        data.into_values().collect()
+    }
+
+    // Blocks grouped by the subroutine they belong to, main program first,
+    // following every edge but calls. Blocks shared by several subroutines
+    // go to the first one.
+    fn clusters(&self) -> Vec<(u16, Vec<&Block>)> {
+        let mut seen = BTreeSet::new();
+        let entries = [self.start_addr]
+            .into_iter()
+            .chain(self.subroutines.iter().copied());
+
+        entries
+            .map(|entry| {
+                let mut blocks = Vec::new();
+                let mut pending = vec![entry];
+
+                while let Some(addr) = pending.pop() {
+                    let Some(block) = self.blocks.get(&addr) else {
+                        continue;
+                    };
+
+                    if !seen.insert(addr) {
+                        continue;
+                    }
+
+                    blocks.push(block);
+                    pending.extend(
+                        block
+                            .edges
+                            .iter()
+                            .filter(|edge| edge.kind != EdgeKind::Call)
+                            .map(|edge| edge.to),
+                    );
+                }
+
+                blocks.sort_by_key(|block| block.start);
+                (entry, blocks)
+            })
+            .filter(|(_, blocks)| !blocks.is_empty())
+            .collect()
+    }
+
+    // The block's listing, left aligned, with a line for each named address.
+    fn block_label(&self, block: &Block, symbols: &Symbols) -> String {
+        (block.start..block.end)
+            .step_by(2)
+            .map(|addr| {
+                let opcode = self.opcode(addr).unwrap();
+                let text =
+                    disasm::disassemble(opcode, symbols).unwrap_or(format!("DW 0x{:04X}", opcode));
+                let label = symbols
+                    .name(addr)
+                    .map_or(String::new(), |name| format!("{}:\\l", name));
+
+                format!("{}0x{:03X}  {}\\l", label, addr, text)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataKind, DataRef, Edge, EdgeKind, Flow};
+    use crate::symbols::Symbols;
+
+    const ROM: [u8; 26] = [
+        0xA2, 0x14, // LD I, 0x214
+        0xD0, 0x15, // DRW V0, V1, 5
+        0x22, 0x10, // CALL 0x210
+        0x30, 0x00, // SE V0, 0
+        0x12, 0x06, // JP 0x206
+        0xB2, 0x0C, // JP V0, 0x20C
+        0x12, 0x0A, // JP 0x20A
+        0xFF, 0xFF, // Unreachable
+        0x70, 0x01, // ADD V0, 1
+        0x00, 0xEE, // RET
+        0xF0, 0x90, 0x90, 0x90, 0xF0, // Sprite
+        0x00, // Unreachable
+    ];
+
+    #[test]
+    fn test_analyze() {
+        let flow = Flow::analyze(&ROM, 0x200);
+        let blocks: Vec<_> = flow.blocks().map(|b| (b.start, b.end)).collect();
+
+        assert_eq!(
+            blocks,
+            [
+                (0x200, 0x206),
+                (0x206, 0x208),
+                (0x208, 0x20A),
+                (0x20A, 0x20C),
+                (0x20C, 0x20E),
+                (0x210, 0x214)
+            ]
+        );
+
+        let first = flow.blocks().next().unwrap();
+        assert_eq!(
+            first.edges,
+            [
+                Edge {
+                    to: 0x210,
+                    kind: EdgeKind::Call
+                },
+                Edge {
+                    to: 0x206,
+                    kind: EdgeKind::Next
+                }
+            ]
+        );
+        assert_eq!(flow.blocks().nth(2).unwrap().edges[0].kind, EdgeKind::Jump);
+        assert_eq!(
+            flow.blocks().nth(3).unwrap().edges[0].kind,
+            EdgeKind::Indirect
+        );
+
+        assert_eq!(
+            flow.subroutines().iter().copied().collect::<Vec<_>>(),
+            [0x210]
+        );
+        assert_eq!(
+            flow.data(),
+            [DataRef {
+                addr: 0x214,
+                len: 5,
+                kind: DataKind::Sprite
+            }]
+        );
+        assert_eq!(flow.unreachable(), [0x20E..0x210, 0x219..0x21A]);
+
+        let dot = flow.to_dot(&Symbols::default());
+        assert!(dot.starts_with("// Sprite at 0x214, 5 bytes\n"));
+        assert!(dot.contains("// Unreachable: 0x20E-0x20F, 2 bytes\n"));
+        assert!(dot.contains("subgraph cluster_210 {\n        label=\"sub_210\";"));
+        assert!(dot.contains("b200 -> b210 [label=\"call\", style=dashed];"));
+        assert!(dot.contains("0x208  JP 0x206\\l"));
+
+        let symbols = Symbols::parse("loop 0x206\nbump 0x210\nball 0x214").unwrap();
+        let dot = flow.to_dot(&symbols);
+        assert!(dot.starts_with("// Sprite at ball, 5 bytes\n"));
+        assert!(dot.contains("subgraph cluster_210 {\n        label=\"bump\";"));
+        assert!(dot.contains("0x204  CALL bump\\l"));
+        assert!(dot.contains("loop:\\l0x206  SE V0, 0x00\\l"));
+        assert!(dot.contains("0x208  JP loop\\l"));
+    }
+}
@@ -1,19 +1,47 @@
-use sdl2::{event::Event, keyboard::Keycode, EventPump};
+use sdl2::{
+    controller::GameController,
+    event::Event,
+    keyboard::{Keycode, Scancode},
+    EventPump, GameControllerSubsystem,
+};
+
+use crate::keymap::KeyMap;
 
 pub const KEY_QUIT: usize = 16;
+pub const KEY_RECORD_AUDIO: usize = 17;
+pub const KEY_RECORD_VIDEO: usize = 18;
+pub const KEY_PALETTE: usize = 19;
+pub const KEY_PAUSE: usize = 20;
+pub const KEY_RESET: usize = 21;
+pub const KEY_HARD_RESET: usize = 22;
+pub const KEY_FRAME_ADVANCE: usize = 23;
 
-const KEYS_SIZE: usize = 17;
+const KEYS_SIZE: usize = 24;
 
 pub struct Input {
     event_pump: EventPump,
+    controller_subsystem: GameControllerSubsystem,
+    controllers: Vec<GameController>,
+    keymap: KeyMap,
     keys: [bool; KEYS_SIZE],
+    seen: [bool; KEYS_SIZE],
+    dropped_file: Option<String>,
 }
 
 impl Input {
-    pub fn init(event_pump: EventPump) -> Self {
+    pub fn init(
+        event_pump: EventPump,
+        controller_subsystem: GameControllerSubsystem,
+        keymap: KeyMap,
+    ) -> Self {
         Self {
             event_pump,
+            controller_subsystem,
+            controllers: Vec::new(),
+            keymap,
             keys: [false; KEYS_SIZE],
+            seen: [false; KEYS_SIZE],
+            dropped_file: None,
         }
     }
 
@@ -26,23 +54,46 @@ impl Input {
                     ..
                 } => self.keys[KEY_QUIT] = true,
                 Event::KeyDown {
-                    keycode: Some(keycode),
+                    keycode,
+                    scancode: Some(scancode),
                     repeat: false,
                     ..
                 } => {
-                    if let Some(key) = Input::get_key(keycode) {
+                    if let Some(key) = Input::get_key(&self.keymap, keycode, scancode) {
                         self.keys[key] = true;
                     }
                 }
                 Event::KeyUp {
-                    keycode: Some(keycode),
+                    keycode,
+                    scancode: Some(scancode),
                     repeat: false,
                     ..
                 } => {
-                    if let Some(key) = Input::get_key(keycode) {
+                    if let Some(key) = Input::get_key(&self.keymap, keycode, scancode) {
+                        self.keys[key] = false;
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    // Controllers stop sending events once dropped.
+                    if let Ok(controller) = self.controller_subsystem.open(which) {
+                        println!("Controller connected: {}", controller.name());
+                        self.controllers.push(controller);
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.controllers.retain(|c| c.instance_id() != which);
+                }
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(key) = self.keymap.button(button) {
+                        self.keys[key] = true;
+                    }
+                }
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(key) = self.keymap.button(button) {
                         self.keys[key] = false;
                     }
                 }
+                Event::DropFile { filename, .. } => self.dropped_file = Some(filename),
                 _ => {}
             }
         }
@@ -50,25 +101,34 @@ impl Input {
         &self.keys
     }
 
-    fn get_key(keycode: Keycode) -> Option<usize> {
-        Some(match keycode {
-            Keycode::Num1 => 1,
-            Keycode::Num2 => 2,
-            Keycode::Num3 => 3,
-            Keycode::Q => 4,
-            Keycode::W => 5,
-            Keycode::E => 6,
-            Keycode::A => 7,
-            Keycode::S => 8,
-            Keycode::D => 9,
-            Keycode::Z => 0xA,
-            Keycode::X => 0,
-            Keycode::C => 0xB,
-            Keycode::V => 0xF,
-            Keycode::F => 0xE,
-            Keycode::R => 0xD,
-            Keycode::Num4 => 0xC,
-            _ => return None,
-        })
+    pub fn set_keymap(&mut self, keymap: KeyMap) {
+        self.keymap = keymap;
+    }
+
+    // The last file dragged into the window, if not taken yet.
+    pub fn dropped_file(&mut self) -> Option<String> {
+        self.dropped_file.take()
+    }
+
+    // Returns true only once per key press, for hotkeys.
+    pub fn pressed(&mut self, key: usize) -> bool {
+        let pressed = self.keys[key] && !self.seen[key];
+        self.seen[key] = self.keys[key];
+
+        pressed
+    }
+
+    // Hotkeys go by symbol, the keypad by physical position.
+    fn get_key(keymap: &KeyMap, keycode: Option<Keycode>, scancode: Scancode) -> Option<usize> {
+        match keycode {
+            Some(Keycode::F2) => Some(KEY_RESET),
+            Some(Keycode::F3) => Some(KEY_HARD_RESET),
+            Some(Keycode::F5) => Some(KEY_PAUSE),
+            Some(Keycode::F6) => Some(KEY_PALETTE),
+            Some(Keycode::F7) => Some(KEY_FRAME_ADVANCE),
+            Some(Keycode::F9) => Some(KEY_RECORD_AUDIO),
+            Some(Keycode::F10) => Some(KEY_RECORD_VIDEO),
+            _ => keymap.key(scancode),
+        }
     }
 }
@@ -0,0 +1,184 @@
+use sdl2::{controller::Button, keyboard::Scancode};
+use toml::{Table, Value};
+
+const NUM_KEYS: usize = 16;
+
+// Physical positions, so the layout is the same on QWERTY, AZERTY, Dvorak...
+//   1 2 3 4        1 2 3 C
+//   Q W E R   ->   4 5 6 D
+//   A S D F        7 8 9 E
+//   Z X C V        A 0 B F
+const DEFAULT_KEYS: [Scancode; NUM_KEYS] = [
+    Scancode::X,
+    Scancode::Num1,
+    Scancode::Num2,
+    Scancode::Num3,
+    Scancode::Q,
+    Scancode::W,
+    Scancode::E,
+    Scancode::A,
+    Scancode::S,
+    Scancode::D,
+    Scancode::Z,
+    Scancode::C,
+    Scancode::Num4,
+    Scancode::R,
+    Scancode::F,
+    Scancode::V,
+];
+
+// Most games only use 2, 4, 6, 8 (directions) and 5 (action).
+const DEFAULT_BUTTONS: [(Button, usize); 10] = [
+    (Button::DPadUp, 0x2),
+    (Button::DPadLeft, 0x4),
+    (Button::DPadRight, 0x6),
+    (Button::DPadDown, 0x8),
+    (Button::A, 0x5),
+    (Button::B, 0xA),
+    (Button::X, 0xB),
+    (Button::Y, 0xC),
+    (Button::Back, 0x0),
+    (Button::Start, 0xF),
+];
+
+// Maps keyboard scancodes and game controller buttons to CHIP-8 keys.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMap {
+    keys: [Vec<Scancode>; NUM_KEYS],
+    buttons: [Vec<Button>; NUM_KEYS],
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        let mut buttons: [Vec<Button>; NUM_KEYS] = Default::default();
+
+        for (button, key) in DEFAULT_BUTTONS {
+            buttons[key].push(button);
+        }
+
+        Self {
+            keys: DEFAULT_KEYS.map(|scancode| vec![scancode]),
+            buttons,
+        }
+    }
+}
+
+impl KeyMap {
+    pub fn key(&self, scancode: Scancode) -> Option<usize> {
+        self.keys.iter().position(|s| s.contains(&scancode))
+    }
+
+    pub fn button(&self, button: Button) -> Option<usize> {
+        self.buttons.iter().position(|b| b.contains(&button))
+    }
+
+    // Replaces the bindings of every CHIP-8 key present in the table:
+    //
+    //   [keys]
+    //   5 = ["W", "Up"]       # CHIP-8 key = SDL scancode names
+    //   [buttons]
+    //   5 = "a"               # CHIP-8 key = SDL controller button names
+    pub fn apply(&mut self, table: &Table) -> Result<(), String> {
+        if let Some(keys) = table.get("keys") {
+            for (key, names) in bindings(keys)? {
+                self.keys[key] = names
+                    .iter()
+                    .map(|name| Scancode::from_name(name).ok_or(format!("Unknown key: {}", name)))
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+
+        if let Some(buttons) = table.get("buttons") {
+            for (key, names) in bindings(buttons)? {
+                self.buttons[key] = names
+                    .iter()
+                    .map(|name| {
+                        Button::from_string(name).ok_or(format!("Unknown button: {}", name))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Reads a table of CHIP-8 key (hex digit) to one or more names.
+fn bindings(value: &Value) -> Result<Vec<(usize, Vec<String>)>, String> {
+    let table = value.as_table().ok_or("Expected a table of bindings.")?;
+
+    table
+        .iter()
+        .map(|(key, names)| {
+            let digits = key.trim_start_matches("0x");
+            let key = match usize::from_str_radix(digits, 16) {
+                Ok(key) if key < NUM_KEYS => key,
+                _ => return Err(format!("Invalid CHIP-8 key: {}", key)),
+            };
+
+            let names = match names {
+                Value::String(name) => vec![name.clone()],
+                Value::Array(names) => names
+                    .iter()
+                    .map(|name| name.as_str().map(str::to_string))
+                    .collect::<Option<_>>()
+                    .ok_or("Expected key names.")?,
+                _ => return Err("Expected key names.".to_string()),
+            };
+
+            Ok((key, names))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::{controller::Button, keyboard::Scancode};
+
+    use super::KeyMap;
+
+    #[test]
+    fn test_default_keymap() {
+        let keymap = KeyMap::default();
+
+        assert_eq!(keymap.key(Scancode::Num1), Some(0x1));
+        assert_eq!(keymap.key(Scancode::Num4), Some(0xC));
+        assert_eq!(keymap.key(Scancode::X), Some(0x0));
+        assert_eq!(keymap.key(Scancode::V), Some(0xF));
+        assert_eq!(keymap.key(Scancode::Escape), None);
+        assert_eq!(keymap.button(Button::DPadUp), Some(0x2));
+        assert_eq!(keymap.button(Button::A), Some(0x5));
+    }
+
+    #[test]
+    fn test_apply_keymap() {
+        let mut keymap = KeyMap::default();
+        let table = "[keys]\n\
+                     5 = [\"W\", \"Up\"]\n\
+                     0xA = \"Space\"\n\
+                     [buttons]\n\
+                     5 = \"b\"\n";
+
+        keymap.apply(&table.parse().unwrap()).unwrap();
+
+        assert_eq!(keymap.key(Scancode::W), Some(0x5));
+        assert_eq!(keymap.key(Scancode::Up), Some(0x5));
+        assert_eq!(keymap.key(Scancode::Space), Some(0xA));
+        assert_eq!(keymap.key(Scancode::Z), None);
+        assert_eq!(keymap.key(Scancode::X), Some(0x0));
+        assert_eq!(keymap.button(Button::B), Some(0x5));
+        assert_eq!(keymap.button(Button::A), None);
+
+        let invalid = [
+            "keys = { 5 = \"Nope\" }",
+            "keys = { G = \"W\" }",
+            "keys = { 5 = 1 }",
+            "keys = 5",
+            "buttons = { 5 = \"Nope\" }",
+        ];
+
+        for table in invalid {
+            assert!(KeyMap::default().apply(&table.parse().unwrap()).is_err());
+        }
+    }
+}
@@ -1,5 +1,35 @@
+#[cfg(feature = "sdl")]
 pub mod audio;
+#[cfg(feature = "sdl")]
+pub mod capture;
 pub mod chip8;
+#[cfg(feature = "sdl")]
+pub mod cli;
+#[cfg(feature = "sdl")]
+pub mod config;
+pub mod decompile;
+pub mod disasm;
+pub mod env;
+pub mod filter;
+pub mod flow;
+#[cfg(feature = "sdl")]
 pub mod input;
+#[cfg(feature = "sdl")]
+pub mod keymap;
 pub mod macros;
+pub mod netplay;
+pub mod overlay;
+#[cfg(feature = "sdl")]
+pub mod palette;
+#[cfg(feature = "python")]
+mod python;
+pub mod reload;
+pub mod romdb;
+pub mod rpc;
+#[cfg(feature = "scripting")]
+pub mod script;
+pub mod symbols;
+#[cfg(feature = "sdl")]
 pub mod video;
+#[cfg(feature = "sdl")]
+pub mod wav;
@@ -1,90 +1,718 @@
 use std::{
-    env, fs, thread,
+    env, io, thread,
     time::{self, Duration, Instant, SystemTime},
 };
 
 use lrchip8::{
-    audio::Audio,
-    chip8::Chip8,
+    audio::{Audio, Tone},
+    capture::VideoRecorder,
+    chip8::{self, Chip8},
+    cli::{self, Command, Options},
+    config::{self, Config},
+    decompile, disasm,
+    flow::Flow,
     input::{self, Input},
+    netplay::{Netplay, Role, Settings},
+    palette::Palette,
+    reload::{self, Reload, RomWatcher},
+    romdb::{self, RomInfo},
+    rpc::Server,
+    symbols::Symbols,
     video::Video,
+    wav::WavRecorder,
 };
-use sdl2::pixels::Color;
 
-const IPS: u32 = 800;
-const FPS: f64 = 60.0;
+#[cfg(feature = "scripting")]
+use lrchip8::script::Script;
 
-const SECS_PER_FRAME: f64 = 1.0 / FPS;
-const ITERS_PER_FRAME: u32 = IPS / FPS as u32;
+const SECS_PER_FRAME: f64 = 1.0 / chip8::FPS as f64;
 
-const VIDEO_SCALE_FACTOR: usize = 12;
+// How often the rom file is checked for changes.
+const WATCH_FRAMES: u64 = 15;
+
+// One minute of emulated time.
+const BENCH_FRAMES: u64 = 3600;
 
 fn main() {
-    let args = env::args().collect::<Vec<String>>();
+    let mut options = match cli::parse_options(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(msg) => {
+            println!("{}", msg);
+            return;
+        }
+    };
+
+    let rom = match reload::read_rom(&options.rom_path, &options.assembler) {
+        Ok(rom) => rom,
+        Err(msg) => {
+            println!("{}", msg);
+            return;
+        }
+    };
+    let rom_hash = config::rom_hash(&rom);
+
+    if matches!(
+        options.command,
+        Command::Disasm | Command::Cfg | Command::Decompile
+    ) {
+        let symbols = load_symbols(&options.rom_path, &options);
+        let start_addr = chip8::ROM_START_ADDR as u16;
+
+        // Addresses past the end of memory don't fit the analysis.
+        if options.command != Command::Disasm && rom.len() > chip8::MAX_ROM_SIZE {
+            println!("The rom is too big to fit in memory.");
+            return;
+        }
+
+        match options.command {
+            Command::Disasm => {
+                for line in disasm::listing(&rom, start_addr, &symbols) {
+                    println!("{}", line);
+                }
+            }
+            Command::Cfg => print!("{}", Flow::analyze(&rom, start_addr).to_dot(&symbols)),
+            _ => print!("{}", decompile::decompile(&rom, start_addr, &symbols)),
+        }
 
-    if args.len() != 2 {
-        println!("Please inform a rom path.");
         return;
     }
 
-    let rom_path = &args[1];
+    println!("ROM SHA-1: {}", rom_hash);
 
-    let rng_seed = SystemTime::now()
-        .duration_since(time::UNIX_EPOCH)
-        .expect("SystemTime before UNIX EPOCH.")
-        .as_secs();
+    let rom_info = romdb::lookup(&rom_hash);
+
+    if let Some(info) = &rom_info {
+        print_rom_info(info);
+    }
+
+    if options.command == Command::Info {
+        println!("Size: {} bytes", rom.len());
+
+        if rom_info.is_none() {
+            println!("Not in the rom database.");
+        }
+
+        return;
+    }
+
+    // Joining a netplay game, the host's settings take precedence.
+    let mut netplay = None;
+
+    if let Some(Role::Connect(addr)) = &options.netplay {
+        println!("Connecting to {}...", addr);
+
+        match Netplay::connect(addr, &rom_hash) {
+            Ok((connection, settings)) => {
+                options.seed = Some(settings.rng_seed);
+                options.ips = Some(settings.ips);
+                options.quirks = Some(settings.quirks);
+                netplay = Some(connection);
+            }
+            Err(msg) => {
+                println!("{}", msg);
+                return;
+            }
+        }
+    }
+
+    let rng_seed = options.seed.unwrap_or_else(unix_secs);
 
     println!("RNG seed: {}", rng_seed);
 
+    let (chip8, config) = match start(
+        &rom,
+        &options.rom_path,
+        rom_info.as_ref(),
+        &options,
+        rng_seed,
+    ) {
+        Ok(started) => started,
+        Err(msg) => {
+            println!("{}", msg);
+            return;
+        }
+    };
+
+    if let Some(Role::Host(port)) = options.netplay {
+        println!("Waiting for the other player on port {}...", port);
+
+        let settings = Settings {
+            rng_seed,
+            ips: config.ips,
+            quirks: config.quirks,
+            delay: options.delay,
+        };
+
+        match Netplay::host(port, &rom_hash, settings) {
+            Ok(connection) => netplay = Some(connection),
+            Err(msg) => {
+                println!("{}", msg);
+                return;
+            }
+        }
+    }
+
+    if let Some(addr) = &options.rpc {
+        let mut server = Server::init(chip8, chip8::iters_per_frame(config.ips));
+
+        if let Err(e) = server.listen(addr) {
+            println!("RPC: {}", e);
+        }
+
+        return;
+    }
+
+    let script = match options.script.as_deref().map(Script::load).transpose() {
+        Ok(script) => script,
+        Err(msg) => {
+            println!("{}", msg);
+            return;
+        }
+    };
+
+    match options.command {
+        Command::Bench => bench(chip8, options.frames.unwrap_or(BENCH_FRAMES), &config),
+        Command::Headless => {
+            run_headless(chip8, options.frames.unwrap(), &options, &config, script);
+        }
+        _ => run(chip8, rom_info, &options, config, netplay, script),
+    }
+}
+
+// Sets up the emulator and the settings for a rom.
+fn start(
+    rom: &[u8],
+    rom_path: &str,
+    rom_info: Option<&RomInfo>,
+    options: &Options,
+    rng_seed: u64,
+) -> Result<(Chip8, Config), String> {
+    let rom_hash = config::rom_hash(rom);
+
+    // A broken config file still leaves the database's settings.
+    let mut config = Config::load(&rom_hash, rom_info).unwrap_or_else(|msg| {
+        println!("{}", msg);
+
+        let mut config = Config::default();
+        if let Some(info) = rom_info {
+            config.apply_rom_info(info);
+        }
+
+        config
+    });
+    options.apply(&mut config);
+
     let mut chip8 = Chip8::init(rng_seed);
-    let rom = fs::read(rom_path).unwrap();
+    chip8.set_quirks(config.quirks);
+    chip8.set_symbols(load_symbols(rom_path, options));
+    chip8.load(rom)?;
+
+    println!("IPS: {}", chip8::iters_per_frame(config.ips) * chip8::FPS);
+
+    if !chip8.symbols().is_empty() {
+        println!("Symbols: {}", chip8.symbols().len());
+    }
+
+    Ok((chip8, config))
+}
+
+fn print_rom_info(info: &RomInfo) {
+    println!("Title: {}", info.title);
+
+    if !info.authors.is_empty() {
+        println!("Authors: {}", info.authors.join(", "));
+    }
+
+    if let Some(release) = &info.release {
+        println!("Release: {}", release);
+    }
+
+    println!("Platform: {}", info.platform_name);
+
+    for (action, key) in &info.keys {
+        println!("Key {:X}: {}", key, action);
+    }
+}
+
+// The symbol file given on the command line is for the first rom. Others,
+// dropped or reloaded, use the one next to them if there is one.
+fn load_symbols(rom_path: &str, options: &Options) -> Symbols {
+    let symbols = match &options.symbols {
+        Some(path) if rom_path == options.rom_path => Symbols::load(path).map(Some),
+        _ => Symbols::for_rom(rom_path),
+    };
+
+    symbols
+        .unwrap_or_else(|msg| {
+            println!("{}", msg);
+            None
+        })
+        .unwrap_or_default()
+}
+
+// Tells when the rom halted, unless it was halted already.
+fn report_fault(chip8: &Chip8, halted: bool) {
+    if let Some(fault) = chip8.fault().filter(|_| !halted) {
+        let pc = chip8.symbols().format(chip8.pc());
+        println!("Halted at {}: {}.", pc, fault);
+    }
+}
 
-    chip8.load(&rom);
+fn run(
+    mut chip8: Chip8,
+    mut rom_info: Option<RomInfo>,
+    options: &Options,
+    mut config: Config,
+    mut netplay: Option<Netplay>,
+    mut script: Option<Script>,
+) {
+    let mut rom_path = options.rom_path.clone();
+    let mut watcher = options
+        .watch
+        .filter(|_| netplay.is_none())
+        .map(|_| RomWatcher::init(&rom_path));
+    let rng_seed = chip8.rng_seed();
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let audio_subsystem = sdl_context.audio().unwrap();
+    let controller_subsystem = sdl_context.game_controller().unwrap();
     let event_pump = sdl_context.event_pump().unwrap();
 
     let mut video = Video::init(
         video_subsystem,
+        rom_info.as_ref().map(|info| info.title.as_str()),
         chip8.video_cols(),
         chip8.video_rows(),
-        VIDEO_SCALE_FACTOR,
-        Color::BLACK,
-        Color::RGB(225, 225, 225),
+        config.scale_factor,
+        config.palette.clone(),
+        config.filter,
     );
+    video.set_fullscreen(options.fullscreen);
+
+    let tone = config.tone;
+    let mut audio = (!options.mute).then(|| Audio::init(audio_subsystem, tone));
+    let mut audio_rec = options
+        .record_audio
+        .as_ref()
+        .and_then(|path| start_audio_rec(path, tone, 0.0));
 
-    let audio = Audio::init(audio_subsystem);
+    let mut video_rec = options
+        .record_video
+        .as_ref()
+        .and_then(|path| start_video_rec(path, &chip8, config.scale_factor, video.palette()));
 
-    let mut input = Input::init(event_pump);
+    let mut input = Input::init(event_pump, controller_subsystem, config.keymap.clone());
 
     let secs_per_frame = Duration::from_secs_f64(SECS_PER_FRAME);
 
-    println!("IPS: {}", ITERS_PER_FRAME * FPS as u32);
+    let mut frame: u64 = 0;
+    let mut paused = false;
 
     'mainloop: loop {
         let start_time = Instant::now();
+        let frame_time = frame as f64 * SECS_PER_FRAME;
+
+        let reload = options.watch.filter(|_| {
+            frame.is_multiple_of(WATCH_FRAMES) && watcher.as_mut().is_some_and(|w| w.changed())
+        });
+
+        // Changing the state on one side only would break netplay.
+        let local = netplay.is_none();
+
+        // A dropped rom replaces the current one, as does reloading it from disk.
+        let new_rom_path = input
+            .dropped_file()
+            .filter(|_| local)
+            .or_else(|| (local && input.pressed(input::KEY_HARD_RESET)).then(|| rom_path.clone()))
+            .or_else(|| (reload == Some(Reload::Reset)).then(|| rom_path.clone()));
+
+        if reload == Some(Reload::Patch) {
+            match reload::read_rom(&rom_path, &options.assembler).and_then(|rom| chip8.patch(&rom))
+            {
+                Ok(()) => {
+                    chip8.set_symbols(load_symbols(&rom_path, options));
+                    println!("Patched {}.", rom_path);
+                }
+                Err(msg) => println!("{}", msg),
+            }
+        }
+
+        if let Some(path) = new_rom_path {
+            // A rom that can't be started leaves the current one running.
+            let started = reload::read_rom(&path, &options.assembler).and_then(|rom| {
+                let rom_hash = config::rom_hash(&rom);
+
+                println!("ROM: {}", path);
+                println!("ROM SHA-1: {}", rom_hash);
+
+                let info = romdb::lookup(&rom_hash);
+                if let Some(info) = &info {
+                    print_rom_info(info);
+                }
+
+                start(&rom, &path, info.as_ref(), options, rng_seed).map(|started| (started, info))
+            });
 
-        chip8.update_timers();
+            match started {
+                Ok(started) => {
+                    ((chip8, config), rom_info) = started;
 
-        for i in 0..ITERS_PER_FRAME {
-            let keys = input.read();
+                    if watcher.as_ref().is_some_and(|w| w.path() != path) {
+                        watcher = Some(RomWatcher::init(&path));
+                    }
+                    rom_path = path;
 
-            if keys[input::KEY_QUIT] {
+                    video.set_title(rom_info.as_ref().map(|info| info.title.as_str()));
+                    video.set_palette(config.palette.clone());
+                    input.set_keymap(config.keymap.clone());
+                }
+                Err(msg) => println!("{}", msg),
+            }
+        }
+
+        if local && input.pressed(input::KEY_RESET) {
+            println!("Reset.");
+            chip8.reset();
+        }
+
+        if local && input.pressed(input::KEY_PAUSE) {
+            paused = !paused;
+            println!("{}", if paused { "Paused." } else { "Resumed." });
+        }
+
+        // Runs a single frame, pausing if not paused yet.
+        let advance = local && input.pressed(input::KEY_FRAME_ADVANCE);
+        if advance {
+            paused = true;
+        }
+
+        if input.pressed(input::KEY_PALETTE) {
+            let palette = video.palette().next();
+            println!("Palette: {}", palette.name);
+            video.set_palette(palette);
+        }
+
+        if input.pressed(input::KEY_RECORD_AUDIO) {
+            audio_rec = match audio_rec.take() {
+                Some(rec) => {
+                    stop_audio_rec(rec);
+                    None
+                }
+                None => {
+                    let path = format!("lrchip8-{}.wav", unix_secs());
+                    start_audio_rec(&path, tone, frame_time)
+                }
+            };
+        }
+
+        if input.pressed(input::KEY_RECORD_VIDEO) {
+            video_rec = match video_rec.take() {
+                Some(rec) => {
+                    stop_video_rec(rec);
+                    None
+                }
+                None => {
+                    let path = format!("lrchip8-{}.gif", unix_secs());
+                    start_video_rec(&path, &chip8, config.scale_factor, video.palette())
+                }
+            };
+        }
+
+        // While paused, time goes on but the emulation and the buzzer stop.
+        let running = !paused || advance;
+        let secs_per_iter = SECS_PER_FRAME / chip8::iters_per_frame(config.ips) as f64;
+
+        // Netplay runs each frame with the keys from both sides.
+        let net_keys = match &mut netplay {
+            Some(netplay) => {
+                let keys = input.read();
+
+                if keys[input::KEY_QUIT] {
+                    break 'mainloop;
+                }
+
+                match netplay.exchange(keys, chip8.state_hash()) {
+                    Ok(keys) => Some(keys),
+                    Err(msg) => {
+                        println!("{}", msg);
+                        break 'mainloop;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let halted = chip8.fault().is_some();
+
+        if running {
+            let mut quit = false;
+
+            chip8.run_frame_with(config.ips, |chip8, i| {
+                if i == 0 {
+                    if let Some(script) = &mut script {
+                        script.on_frame(chip8, frame);
+                    }
+
+                    set_buzzer(&mut audio, &mut audio_rec, chip8.audio(), frame_time);
+                }
+
+                let keys = input.read();
+
+                if keys[input::KEY_QUIT] {
+                    quit = true;
+                    return None;
+                }
+
+                let outcome = match &mut script {
+                    Some(script) => {
+                        chip8.write_keys(&script.keys(keys));
+                        script.tick(chip8)
+                    }
+                    None => {
+                        chip8.write_keys(net_keys.as_ref().map_or(keys, |keys| keys));
+                        chip8.tick()
+                    }
+                };
+
+                let iter_time = frame_time + (i + 1) as f64 * secs_per_iter;
+                set_buzzer(&mut audio, &mut audio_rec, chip8.audio(), iter_time);
+
+                Some(outcome)
+            });
+
+            if quit {
+                break 'mainloop;
+            }
+        } else {
+            if input.read()[input::KEY_QUIT] {
                 break 'mainloop;
             }
 
-            chip8.write_keys(keys);
-            chip8.set_vblank(i == 0);
-            chip8.tick();
+            set_buzzer(&mut audio, &mut audio_rec, false, frame_time);
+        }
+
+        report_fault(&chip8, halted);
+
+        if let Some(audio) = &audio {
+            audio.sync(frame_time + SECS_PER_FRAME);
         }
+        write_audio_rec(&mut audio_rec, |rec| rec.sync(frame_time + SECS_PER_FRAME));
 
-        audio.play(chip8.audio());
+        if let Some(script) = &script {
+            video.set_overlay(script.overlay());
+        }
         video.draw(chip8.video());
+        write_video_rec(&mut video_rec, |rec| rec.record(chip8.video()));
+
+        frame += 1;
 
         thread::sleep(
             secs_per_frame.saturating_sub(Instant::now().saturating_duration_since(start_time)),
         );
     }
+
+    if let Some(rec) = audio_rec {
+        stop_audio_rec(rec);
+    }
+
+    if let Some(rec) = video_rec {
+        stop_video_rec(rec);
+    }
+}
+
+fn run_headless(
+    mut chip8: Chip8,
+    frames: u64,
+    options: &Options,
+    config: &Config,
+    mut script: Option<Script>,
+) {
+    let secs_per_iter = SECS_PER_FRAME / chip8::iters_per_frame(config.ips) as f64;
+
+    let mut audio_rec = options
+        .record_audio
+        .as_ref()
+        .and_then(|path| start_audio_rec(path, config.tone, 0.0));
+    let mut video_rec = options
+        .record_video
+        .as_ref()
+        .and_then(|path| start_video_rec(path, &chip8, config.scale_factor, &config.palette));
+
+    for frame in 0..frames {
+        let frame_time = frame as f64 * SECS_PER_FRAME;
+
+        let halted = chip8.fault().is_some();
+
+        chip8.run_frame_with(config.ips, |chip8, i| {
+            if i == 0 {
+                write_audio_rec(&mut audio_rec, |rec| {
+                    rec.set_buzzer(chip8.audio(), frame_time)
+                });
+
+                if let Some(script) = &mut script {
+                    script.on_frame(chip8, frame);
+                }
+            }
+
+            let outcome = match &mut script {
+                Some(script) => {
+                    chip8.write_keys(&script.keys(&[false; 16]));
+                    script.tick(chip8)
+                }
+                None => chip8.tick(),
+            };
+
+            let iter_time = frame_time + (i + 1) as f64 * secs_per_iter;
+            write_audio_rec(&mut audio_rec, |rec| {
+                rec.set_buzzer(chip8.audio(), iter_time)
+            });
+
+            Some(outcome)
+        });
+
+        report_fault(&chip8, halted);
+
+        write_audio_rec(&mut audio_rec, |rec| rec.sync(frame_time + SECS_PER_FRAME));
+
+        write_video_rec(&mut video_rec, |rec| rec.record(chip8.video()));
+    }
+
+    if let Some(rec) = audio_rec {
+        stop_audio_rec(rec);
+    }
+
+    if let Some(rec) = video_rec {
+        stop_video_rec(rec);
+    }
+}
+
+// Runs the emulation alone as fast as possible: no timing, input or output.
+fn bench(mut chip8: Chip8, frames: u64, config: &Config) {
+    let start_time = Instant::now();
+    let mut iters = 0;
+
+    for _ in 0..frames {
+        iters += chip8.run_frame(config.ips).executed as u64;
+    }
+
+    let secs = start_time.elapsed().as_secs_f64();
+
+    report_fault(&chip8, false);
+
+    println!(
+        "{} frames, {} instructions in {:.3}s: {:.0} IPS, {:.1}x real time.",
+        frames,
+        iters,
+        secs,
+        iters as f64 / secs,
+        frames as f64 * SECS_PER_FRAME / secs
+    );
+}
+
+// Without the scripting feature, --script is refused.
+#[cfg(not(feature = "scripting"))]
+struct Script;
+
+#[cfg(not(feature = "scripting"))]
+impl Script {
+    fn load(_path: &str) -> Result<Self, String> {
+        Err("Scripting is not available, build with --features scripting.".to_string())
+    }
+
+    fn on_frame(&mut self, _chip8: &mut Chip8, _frame: u64) {}
+
+    fn tick(&mut self, chip8: &mut Chip8) -> chip8::StepOutcome {
+        chip8.tick()
+    }
+
+    fn keys(&self, keys: &[bool]) -> [bool; 16] {
+        std::array::from_fn(|i| keys[i])
+    }
+
+    fn overlay(&self) -> Vec<lrchip8::overlay::Text> {
+        Vec::new()
+    }
+}
+
+// Turns the buzzer on or off at a time, in the output and the recording.
+fn set_buzzer(audio: &mut Option<Audio>, audio_rec: &mut Option<WavRecorder>, on: bool, time: f64) {
+    if let Some(audio) = audio {
+        audio.set_buzzer(on, time);
+    }
+    write_audio_rec(audio_rec, |rec| rec.set_buzzer(on, time));
+}
+
+// Recording errors, e.g. a full disk, stop the recording but not the
+// emulation.
+fn start_audio_rec(path: &str, tone: Tone, start_time: f64) -> Option<WavRecorder> {
+    println!("Recording audio to {}...", path);
+
+    WavRecorder::create(path, tone, start_time)
+        .map_err(|e| println!("Could not record audio: {}", e))
+        .ok()
+}
+
+fn write_audio_rec<F: FnOnce(&mut WavRecorder) -> io::Result<()>>(
+    audio_rec: &mut Option<WavRecorder>,
+    write: F,
+) {
+    if let Some(rec) = audio_rec {
+        if let Err(e) = write(rec) {
+            println!("Could not record audio: {}", e);
+            *audio_rec = None;
+        }
+    }
+}
+
+fn stop_audio_rec(rec: WavRecorder) {
+    match rec.finish() {
+        Ok(()) => println!("Audio recording saved."),
+        Err(e) => println!("Could not record audio: {}", e),
+    }
+}
+
+fn start_video_rec(
+    path: &str,
+    chip8: &Chip8,
+    scale_factor: usize,
+    palette: &Palette,
+) -> Option<VideoRecorder> {
+    println!("Recording video to {}...", path);
+
+    VideoRecorder::create(
+        path,
+        chip8.video_cols(),
+        chip8.video_rows(),
+        scale_factor,
+        &palette.colors,
+    )
+    .map_err(|e| println!("Could not record video: {}", e))
+    .ok()
+}
+
+fn write_video_rec<F: FnOnce(&mut VideoRecorder) -> io::Result<()>>(
+    video_rec: &mut Option<VideoRecorder>,
+    write: F,
+) {
+    if let Some(rec) = video_rec {
+        if let Err(e) = write(rec) {
+            println!("Could not record video: {}", e);
+            *video_rec = None;
+        }
+    }
+}
+
+fn stop_video_rec(rec: VideoRecorder) {
+    match rec.finish() {
+        Ok(()) => println!("Video recording saved."),
+        Err(e) => println!("Could not record video: {}", e),
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .expect("SystemTime before UNIX EPOCH.")
+        .as_secs()
 }
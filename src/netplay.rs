@@ -0,0 +1,240 @@
+use std::{
+    collections::VecDeque,
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::chip8::Quirks;
+
+const MAGIC: &[u8; 4] = b"LRC8";
+const VERSION: u8 = 1;
+
+const NUM_KEYS: usize = 16;
+
+// 2 players, 1 keypad: pressing a key on either side presses it for both.
+//
+// Both instances run the same frames with the same keys, so they stay in
+// sync as the core is deterministic. The keys pressed on a frame are only
+// used DELAY frames later, which gives them time to reach the other side.
+pub struct Netplay {
+    stream: TcpStream,
+    delay: u64,
+    frame: u64,
+    // Local keys and state hashes of the frames whose message from the other
+    // side has not arrived yet.
+    local_keys: VecDeque<u16>,
+    hashes: VecDeque<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Role {
+    // Listen on this port.
+    Host(u16),
+    // Connect to this address.
+    Connect(String),
+}
+
+// What both sides must agree on, chosen by the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub rng_seed: u64,
+    pub ips: u32,
+    pub quirks: Quirks,
+    // Input delay, in frames.
+    pub delay: u8,
+}
+
+impl Netplay {
+    // Listens on the port for the other player.
+    pub fn host(port: u16, rom_hash: &str, settings: Settings) -> Result<Self, String> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).map_err(error)?;
+
+        Netplay::accept(&listener, rom_hash, settings)
+    }
+
+    // Waits for the other player and sends them the settings.
+    pub fn accept(
+        listener: &TcpListener,
+        rom_hash: &str,
+        settings: Settings,
+    ) -> Result<Self, String> {
+        let (mut stream, addr) = listener.accept().map_err(error)?;
+
+        println!("Player connected: {}", addr);
+
+        let mut hello = MAGIC.to_vec();
+        hello.push(VERSION);
+        hello.extend(settings.rng_seed.to_be_bytes());
+        hello.extend(settings.ips.to_be_bytes());
+        hello.push(settings.quirks.to_bits());
+        hello.push(settings.delay);
+        hello.extend(rom_hash.as_bytes());
+
+        stream.write_all(&hello).map_err(error)?;
+
+        let mut ok = [0u8];
+        stream.read_exact(&mut ok).map_err(error)?;
+
+        if ok[0] != 1 {
+            return Err("The other player has a different rom or version.".to_string());
+        }
+
+        Netplay::init(stream, settings.delay)
+    }
+
+    // Connects to the host and receives its settings.
+    pub fn connect(addr: &str, rom_hash: &str) -> Result<(Self, Settings), String> {
+        let mut stream = TcpStream::connect(addr).map_err(error)?;
+
+        let mut hello = [0u8; 19];
+        stream.read_exact(&mut hello).map_err(error)?;
+
+        let mut host_rom_hash = vec![0u8; rom_hash.len()];
+        stream.read_exact(&mut host_rom_hash).map_err(error)?;
+
+        let compatible = &hello[..4] == MAGIC && hello[4] == VERSION;
+        let same_rom = host_rom_hash == rom_hash.as_bytes();
+
+        stream
+            .write_all(&[(compatible && same_rom) as u8])
+            .map_err(error)?;
+
+        if !compatible {
+            return Err("The other player has a different version.".to_string());
+        }
+
+        if !same_rom {
+            return Err("The other player has a different rom.".to_string());
+        }
+
+        let settings = Settings {
+            rng_seed: u64::from_be_bytes(hello[5..13].try_into().unwrap()),
+            ips: u32::from_be_bytes(hello[13..17].try_into().unwrap()),
+            quirks: Quirks::from_bits(hello[17]),
+            delay: hello[18],
+        };
+
+        Ok((Netplay::init(stream, settings.delay)?, settings))
+    }
+
+    fn init(stream: TcpStream, delay: u8) -> Result<Self, String> {
+        stream.set_nodelay(true).map_err(error)?;
+
+        Ok(Self {
+            stream,
+            delay: delay as u64,
+            frame: 0,
+            local_keys: VecDeque::new(),
+            hashes: VecDeque::new(),
+        })
+    }
+
+    // Sends this frame's keys and state hash, then returns the keypad to run
+    // the frame with. Waits for the other side when it falls behind.
+    pub fn exchange(&mut self, keys: &[bool], state_hash: u64) -> Result<[bool; NUM_KEYS], String> {
+        let frame = self.frame;
+        let local_keys = keys_to_bits(keys);
+
+        let mut message = [0u8; 18];
+        message[..8].copy_from_slice(&frame.to_be_bytes());
+        message[8..10].copy_from_slice(&local_keys.to_be_bytes());
+        message[10..].copy_from_slice(&state_hash.to_be_bytes());
+        self.stream.write_all(&message).map_err(error)?;
+
+        self.local_keys.push_back(local_keys);
+        self.hashes.push_back(state_hash);
+        self.frame += 1;
+
+        // No keys were pressed before the first frame.
+        if frame < self.delay {
+            return Ok([false; NUM_KEYS]);
+        }
+
+        self.stream.read_exact(&mut message).map_err(error)?;
+
+        let remote_frame = u64::from_be_bytes(message[..8].try_into().unwrap());
+        let remote_keys = u16::from_be_bytes(message[8..10].try_into().unwrap());
+        let remote_hash = u64::from_be_bytes(message[10..].try_into().unwrap());
+
+        let local_keys = self.local_keys.pop_front().unwrap();
+        let local_hash = self.hashes.pop_front().unwrap();
+
+        if remote_frame != frame - self.delay {
+            return Err(format!("Unexpected frame: {}", remote_frame));
+        }
+
+        if remote_hash != local_hash {
+            return Err(format!("Desync detected on frame {}.", remote_frame));
+        }
+
+        Ok(keys_from_bits(local_keys | remote_keys))
+    }
+}
+
+fn error(e: std::io::Error) -> String {
+    format!("Netplay: {}", e)
+}
+
+fn keys_to_bits(keys: &[bool]) -> u16 {
+    keys[..NUM_KEYS]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &key)| bits | (key as u16) << i)
+}
+
+fn keys_from_bits(bits: u16) -> [bool; NUM_KEYS] {
+    std::array::from_fn(|i| bits & (1 << i) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::{Netplay, Settings};
+    use crate::chip8::Quirks;
+
+    #[test]
+    fn test_netplay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let settings = Settings {
+            rng_seed: 7,
+            ips: 900,
+            quirks: Quirks::SCHIP,
+            delay: 2,
+        };
+
+        let client = thread::spawn(move || {
+            let (mut netplay, settings) = Netplay::connect(&addr, "abc").unwrap();
+            let mut keys = [false; 16];
+            keys[0xC] = true;
+
+            // Different state on frame 2, noticed on frame 2 + delay.
+            let pressed: Vec<_> = (0..4)
+                .map(|frame| netplay.exchange(&keys, frame * 10).unwrap())
+                .collect();
+
+            (settings, pressed, netplay.exchange(&keys, 40))
+        });
+
+        let mut host = Netplay::accept(&listener, "abc", settings).unwrap();
+        let mut keys = [false; 16];
+        keys[0x1] = true;
+
+        let pressed: Vec<_> = (0..4)
+            .map(|frame| {
+                host.exchange(&keys, frame * 10 + (frame == 2) as u64)
+                    .unwrap()
+            })
+            .collect();
+        assert!(host.exchange(&keys, 40).is_err());
+
+        let (client_settings, client_pressed, desync) = client.join().unwrap();
+
+        assert_eq!(client_settings, settings);
+        assert_eq!(pressed, client_pressed);
+        assert!(!pressed[1][0x1]);
+        assert!(pressed[2][0x1] && pressed[2][0xC]);
+        assert!(desync.is_err());
+    }
+}
@@ -0,0 +1,138 @@
+// Text drawn over the screen, e.g. by scripts, with a tiny 3x5 font.
+
+const GLYPH_COLS: usize = 3;
+const GLYPH_ROWS: usize = 5;
+
+// Glyphs are drawn one column and one row apart.
+const ADVANCE_X: usize = GLYPH_COLS + 1;
+const ADVANCE_Y: usize = GLYPH_ROWS + 1;
+
+// One byte per row, the 3 lowest bits from left to right.
+const FONT: [(char, [u8; GLYPH_ROWS]); 53] = [
+    (' ', [0b000, 0b000, 0b000, 0b000, 0b000]),
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b110, 0b001, 0b010, 0b100, 0b111]),
+    ('3', [0b110, 0b001, 0b010, 0b001, 0b110]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b110, 0b001, 0b110]),
+    ('6', [0b011, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b010, 0b010, 0b010]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b110]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    (';', [0b000, 0b010, 0b000, 0b010, 0b100]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b110, 0b001, 0b010, 0b000, 0b010]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('(', [0b001, 0b010, 0b010, 0b010, 0b001]),
+    (')', [0b100, 0b010, 0b010, 0b010, 0b100]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('_', [0b000, 0b000, 0b000, 0b000, 0b111]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Text {
+    // Position of the top left corner, in font pixels.
+    pub x: i32,
+    pub y: i32,
+    pub text: String,
+}
+
+impl Text {
+    // The lit font pixels, relative to the text position. Letters are shown
+    // in uppercase and unknown characters as '?'.
+    pub fn pixels(&self) -> Vec<(i32, i32)> {
+        let mut pixels = Vec::new();
+
+        for (row, line) in self.text.lines().enumerate() {
+            for (col, c) in line.chars().enumerate() {
+                let glyph = glyph(c.to_ascii_uppercase());
+
+                for (y, bits) in glyph.iter().enumerate() {
+                    for x in 0..GLYPH_COLS {
+                        if bits & (0b100 >> x) != 0 {
+                            pixels
+                                .push(((col * ADVANCE_X + x) as i32, (row * ADVANCE_Y + y) as i32));
+                        }
+                    }
+                }
+            }
+        }
+
+        pixels
+    }
+
+    // Width and height, in font pixels.
+    pub fn size(&self) -> (usize, usize) {
+        let cols = self.text.lines().map(|line| line.chars().count()).max();
+        let rows = self.text.lines().count();
+
+        (
+            (cols.unwrap_or(0) * ADVANCE_X).saturating_sub(1),
+            (rows * ADVANCE_Y).saturating_sub(1),
+        )
+    }
+}
+
+fn glyph(c: char) -> [u8; GLYPH_ROWS] {
+    let find = |c| FONT.iter().find(|(glyph_char, _)| *glyph_char == c);
+
+    find(c).or_else(|| find('?')).unwrap().1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Text;
+
+    #[test]
+    fn test_text_pixels() {
+        let text = Text {
+            x: 0,
+            y: 0,
+            text: "1.\n~".to_string(),
+        };
+
+        let pixels = text.pixels();
+
+        // '1' then '.' one column apart.
+        assert_eq!(&pixels[..3], &[(1, 0), (0, 1), (1, 1)]);
+        assert!(pixels.contains(&(5, 4)));
+        // Unknown characters are shown as '?', on the second line.
+        assert_eq!(pixels[pixels.len() - 1], (1, 10));
+        assert_eq!(text.size(), (7, 11));
+    }
+}
@@ -0,0 +1,115 @@
+use sdl2::pixels::Color;
+
+// Background, first plane, second plane and both planes, as in Octo.
+const PALETTES: [(&str, [u32; 4]); 10] = [
+    ("classic", [0x000000, 0xE1E1E1, 0x808080, 0xFFFFFF]),
+    ("amber", [0x1A0F00, 0xFFB000, 0x995F00, 0xFFD280]),
+    ("green", [0x001A05, 0x33FF66, 0x1A9933, 0xB3FFC6]),
+    ("lcd", [0xF9FFB3, 0x3D8026, 0xABCC47, 0x00131A]),
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+    ("hotdog", [0x000000, 0xFF0000, 0xFFFF00, 0xFFFFFF]),
+    ("gray", [0xAAAAAA, 0x000000, 0xFFFFFF, 0x666666]),
+    ("cga0", [0x000000, 0x00FF00, 0xFF0000, 0xFFFF00]),
+    ("cga1", [0x000000, 0xFF00FF, 0x00FFFF, 0xFFFFFF]),
+    ("neon", [0x0B0B1A, 0xFF2E97, 0x2EF2FF, 0xFFFFFF]),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [Color; 4],
+}
+
+impl Palette {
+    pub fn named(name: &str) -> Option<Self> {
+        PALETTES
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(name, colors)| Self {
+                name: name.to_string(),
+                colors: colors.map(rgb),
+            })
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        PALETTES.iter().map(|&(name, _)| name)
+    }
+
+    // Parses a palette name or a list of 2 to 4 comma separated hex colors,
+    // e.g. "amber" or "#000000,#FFFFFF".
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        if let Some(palette) = Palette::named(spec) {
+            return Ok(palette);
+        }
+
+        let colors = spec
+            .split(',')
+            .map(|s| parse_color(s.trim()))
+            .collect::<Result<Vec<Color>, String>>()?;
+
+        let colors = match colors[..] {
+            // Both planes share the single foreground color.
+            [bg, fg] => [bg, fg, fg, fg],
+            [bg, fg1, fg2] => [bg, fg1, fg2, fg1],
+            [bg, fg1, fg2, fg3] => [bg, fg1, fg2, fg3],
+            _ => return Err(format!("Expected 2 to 4 colors: {}", spec)),
+        };
+
+        Ok(Self {
+            name: "custom".to_string(),
+            colors,
+        })
+    }
+
+    // The next named palette, wrapping around.
+    pub fn next(&self) -> Self {
+        let i = PALETTES
+            .iter()
+            .position(|(name, _)| *name == self.name)
+            .map_or(0, |i| (i + 1) % PALETTES.len());
+
+        Palette::named(PALETTES[i].0).unwrap()
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::named("classic").unwrap()
+    }
+}
+
+pub fn parse_color(s: &str) -> Result<Color, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("Invalid color: {}", s));
+    }
+
+    Ok(rgb(u32::from_str_radix(hex, 16).unwrap()))
+}
+
+fn rgb(value: u32) -> Color {
+    Color::RGB((value >> 16) as u8, (value >> 8) as u8, value as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use sdl2::pixels::Color;
+
+    use super::Palette;
+
+    #[test]
+    fn test_parse_palette() {
+        let palette = Palette::parse("AMBER").unwrap();
+        assert_eq!(palette.name, "amber");
+        assert_eq!(palette.next().name, "green");
+
+        let palette = Palette::parse("#102030, ffffff").unwrap();
+        assert_eq!(palette.colors[0], Color::RGB(0x10, 0x20, 0x30));
+        assert_eq!(palette.colors[3], Color::WHITE);
+        assert_eq!(palette.next().name, "classic");
+
+        assert!(Palette::parse("#000000").is_err());
+        assert!(Palette::parse("#00000g,#ffffff").is_err());
+    }
+}
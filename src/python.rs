@@ -0,0 +1,180 @@
+// Python extension module, built with maturin (see pyproject.toml):
+//
+//     import lrchip8
+//     import numpy as np
+//
+//     chip8 = lrchip8.Chip8(seed=1, quirks="schip", ips=700)
+//     chip8.load(open("pong.ch8", "rb").read())
+//     chip8.write_keys([False] * 16)
+//     chip8.run_frame()
+//     screen = np.frombuffer(chip8.video(), dtype=np.uint8).reshape(32, 64)
+
+use pyo3::{
+    exceptions::{PyIndexError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+
+use crate::chip8::{Chip8, Quirks};
+
+const NUM_KEYS: usize = 16;
+
+#[pyclass(name = "Chip8")]
+struct PyChip8 {
+    chip8: Chip8,
+    ips: u32,
+}
+
+#[pymethods]
+impl PyChip8 {
+    #[new]
+    #[pyo3(signature = (seed = 0, quirks = "default", ips = 700))]
+    fn new(seed: u64, quirks: &str, ips: u32) -> PyResult<Self> {
+        let mut chip8 = Chip8::init(seed);
+        chip8.set_quirks(Quirks::parse(quirks).map_err(PyValueError::new_err)?);
+
+        Ok(Self { chip8, ips })
+    }
+
+    fn load(&mut self, rom: &[u8]) -> PyResult<()> {
+        self.chip8.load(rom).map_err(PyValueError::new_err)
+    }
+
+    fn reset(&mut self) {
+        self.chip8.reset();
+    }
+
+    // Runs one instruction.
+    fn tick(&mut self) {
+        self.chip8.tick();
+    }
+
+    // Runs a 60th of a second: timers, then the instructions of a frame.
+    // Returns how many ran, fewer when the rom waits or halts.
+    fn run_frame(&mut self) -> u32 {
+        self.chip8.run_frame(self.ips).executed
+    }
+
+    // 16 booleans, one per key.
+    fn write_keys(&mut self, keys: Vec<bool>) -> PyResult<()> {
+        if keys.len() != NUM_KEYS {
+            return Err(PyValueError::new_err("Expected 16 keys."));
+        }
+
+        self.chip8.write_keys(&keys);
+        Ok(())
+    }
+
+    // One byte per pixel, row by row, for numpy.frombuffer.
+    fn video<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.chip8.video())
+    }
+
+    #[getter]
+    fn video_cols(&self) -> usize {
+        self.chip8.video_cols()
+    }
+
+    #[getter]
+    fn video_rows(&self) -> usize {
+        self.chip8.video_rows()
+    }
+
+    // Whether the buzzer is on.
+    fn audio(&self) -> bool {
+        self.chip8.audio()
+    }
+
+    fn save_state<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.chip8.save_state())
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> PyResult<()> {
+        self.chip8.load_state(state).map_err(PyValueError::new_err)
+    }
+
+    fn read_memory<'py>(
+        &self,
+        py: Python<'py>,
+        addr: usize,
+        len: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let data = self
+            .chip8
+            .read_mem(addr, len)
+            .map_err(PyIndexError::new_err)?;
+
+        Ok(PyBytes::new(py, data))
+    }
+
+    fn write_memory(&mut self, addr: usize, data: &[u8]) -> PyResult<()> {
+        self.chip8
+            .write_mem(addr, data)
+            .map_err(PyIndexError::new_err)
+    }
+
+    // V0 to VF.
+    #[getter]
+    fn v(&self) -> Vec<u8> {
+        self.chip8.regs().to_vec()
+    }
+
+    fn set_v(&mut self, x: usize, value: u8) -> PyResult<()> {
+        self.chip8.set_reg(x, value).map_err(PyIndexError::new_err)
+    }
+
+    #[getter]
+    fn i(&self) -> u16 {
+        self.chip8.i()
+    }
+
+    #[setter]
+    fn set_i(&mut self, i: u16) {
+        self.chip8.set_i(i);
+    }
+
+    #[getter]
+    fn pc(&self) -> u16 {
+        self.chip8.pc()
+    }
+
+    #[setter]
+    fn set_pc(&mut self, pc: u16) -> PyResult<()> {
+        self.chip8.set_pc(pc).map_err(PyIndexError::new_err)
+    }
+
+    #[getter]
+    fn sp(&self) -> u8 {
+        self.chip8.sp()
+    }
+
+    #[getter]
+    fn stack(&self) -> Vec<u16> {
+        self.chip8.stack().to_vec()
+    }
+
+    #[getter]
+    fn delay_timer(&self) -> u8 {
+        self.chip8.delay_timer()
+    }
+
+    #[setter]
+    fn set_delay_timer(&mut self, value: u8) {
+        self.chip8.set_delay_timer(value);
+    }
+
+    #[getter]
+    fn sound_timer(&self) -> u8 {
+        self.chip8.sound_timer()
+    }
+
+    #[setter]
+    fn set_sound_timer(&mut self, value: u8) {
+        self.chip8.set_sound_timer(value);
+    }
+}
+
+#[pymodule]
+fn lrchip8(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyChip8>()
+}
@@ -0,0 +1,154 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    time::SystemTime,
+};
+
+pub const DEFAULT_ASSEMBLER: &str = "octo";
+
+// What to do with the running rom when its file changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reload {
+    // Start over, as with a hard reset.
+    Reset,
+    // Write the new rom over the old one in memory, keeping registers,
+    // timers, stack and screen.
+    Patch,
+}
+
+impl Reload {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        match spec {
+            "reset" => Ok(Reload::Reset),
+            "patch" => Ok(Reload::Patch),
+            _ => Err(format!("Unknown reload mode: {}", spec)),
+        }
+    }
+}
+
+// Reads a rom, assembling it first if it is an Octo source (.8o). The
+// assembler is run as "<assembler> source.8o out.ch8".
+pub fn read_rom(path: &str, assembler: &str) -> Result<Vec<u8>, String> {
+    if !is_octo_source(path) {
+        return fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    }
+
+    let out_path = assembled_path(path);
+
+    let output = process::Command::new(assembler)
+        .arg(path)
+        .arg(&out_path)
+        .output()
+        .map_err(|e| format!("{}: {}", assembler, e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "{}: {}{}",
+            path,
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let rom = fs::read(&out_path).map_err(|e| format!("{}: {}", out_path.display(), e));
+    let _ = fs::remove_file(&out_path);
+
+    rom
+}
+
+fn is_octo_source(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("8o"))
+}
+
+fn assembled_path(path: &str) -> PathBuf {
+    let stem = Path::new(path)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+
+    env::temp_dir().join(format!("lrchip8-{}-{}.ch8", process::id(), stem))
+}
+
+// Polls a file's modification time. Editors often write files in several
+// steps, so a change is only reported once the time stays the same between
+// two polls.
+pub struct RomWatcher {
+    path: String,
+    modified: Option<SystemTime>,
+    pending: Option<SystemTime>,
+}
+
+impl RomWatcher {
+    pub fn init(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            modified: modified(path),
+            pending: None,
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn changed(&mut self) -> bool {
+        let modified = modified(&self.path);
+
+        if modified.is_none() || modified == self.modified {
+            self.pending = None;
+            false
+        } else if modified == self.pending {
+            self.modified = modified;
+            self.pending = None;
+            true
+        } else {
+            self.pending = modified;
+            false
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        env, fs,
+        time::{Duration, SystemTime},
+    };
+
+    use super::{read_rom, RomWatcher};
+
+    #[test]
+    fn test_rom_watcher() {
+        let path = env::temp_dir().join(format!("lrchip8-test-{}.8o", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        let touch = |secs| {
+            let file = fs::File::options().write(true).open(&path).unwrap();
+            file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+                .unwrap();
+        };
+
+        fs::write(&path, [0x00, 0xE0]).unwrap();
+        touch(1000);
+
+        let mut watcher = RomWatcher::init(path_str);
+        assert!(!watcher.changed());
+
+        touch(2000);
+        assert!(!watcher.changed());
+        assert!(watcher.changed());
+        assert!(!watcher.changed());
+
+        // Any command taking the source and output paths works.
+        assert_eq!(read_rom(path_str, "cp"), Ok(vec![0x00, 0xE0]));
+        assert!(read_rom(path_str, "false").is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
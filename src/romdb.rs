@@ -0,0 +1,159 @@
+// ROM metadata in the format of the community CHIP-8 database:
+// https://github.com/chip-8/chip-8-database
+//
+// The files in db/ are a stub with the roms in rom/ only. The upstream ones
+// can be dropped in their place.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Deserialize;
+
+use crate::chip8::Quirks;
+
+const PROGRAMS: &str = include_str!("../db/programs.json");
+const HASHES: &str = include_str!("../db/sha1-hashes.json");
+const PLATFORMS: &str = include_str!("../db/platforms.json");
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    release: Option<String>,
+    #[serde(default)]
+    authors: Vec<String>,
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    #[serde(default)]
+    platforms: Vec<String>,
+    #[serde(default)]
+    quirky_platforms: HashMap<String, PlatformQuirks>,
+    #[serde(default)]
+    tickrate: Option<u32>,
+    #[serde(default)]
+    colors: Option<Colors>,
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Platform {
+    id: String,
+    name: String,
+    default_tickrate: u32,
+    quirks: PlatformQuirks,
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlatformQuirks {
+    shift: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl PlatformQuirks {
+    fn apply(&self, quirks: &mut Quirks) {
+        let flags = [
+            (self.shift, &mut quirks.shifting, false),
+            (self.memory_leave_i_unchanged, &mut quirks.memory, true),
+            (self.wrap, &mut quirks.clipping, true),
+            (self.jump, &mut quirks.jumping, false),
+            (self.vblank, &mut quirks.display_wait, false),
+            (self.logic, &mut quirks.vf_reset, false),
+        ];
+
+        for (value, flag, inverted) in flags {
+            if let Some(value) = value {
+                *flag = value != inverted;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RomInfo {
+    pub title: String,
+    pub description: Option<String>,
+    pub release: Option<String>,
+    pub authors: Vec<String>,
+    pub platform: String,
+    pub platform_name: String,
+    pub quirks: Quirks,
+    // Instructions per frame.
+    pub tickrate: u32,
+    pub colors: Vec<String>,
+    // Key hints, e.g. "up" = 1.
+    pub keys: BTreeMap<String, u8>,
+}
+
+// Looks a rom up by its SHA-1 hash.
+pub fn lookup(rom_hash: &str) -> Option<RomInfo> {
+    let hashes: HashMap<String, usize> = serde_json::from_str(HASHES).unwrap();
+    let mut programs: Vec<Program> = serde_json::from_str(PROGRAMS).unwrap();
+    let platforms: Vec<Platform> = serde_json::from_str(PLATFORMS).unwrap();
+
+    let index = *hashes.get(rom_hash)?;
+    let program = programs.get_mut(index)?;
+    let rom = program.roms.remove(rom_hash)?;
+
+    // The first listed platform is the preferred one.
+    let platform = rom
+        .platforms
+        .first()
+        .and_then(|id| platforms.iter().find(|p| &p.id == id))?;
+
+    let mut quirks = Quirks::default();
+    platform.quirks.apply(&mut quirks);
+
+    if let Some(rom_quirks) = rom.quirky_platforms.get(&platform.id) {
+        rom_quirks.apply(&mut quirks);
+    }
+
+    Some(RomInfo {
+        title: program.title.clone(),
+        description: program.description.take(),
+        release: program.release.take(),
+        authors: program.authors.clone(),
+        platform: platform.id.clone(),
+        platform_name: platform.name.clone(),
+        quirks,
+        tickrate: rom.tickrate.unwrap_or(platform.default_tickrate),
+        colors: rom.colors.map(|c| c.pixels).unwrap_or_default(),
+        keys: rom.keys,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lookup;
+    use crate::chip8::Quirks;
+
+    #[test]
+    fn test_lookup() {
+        let info = lookup("607c4f7f4e4dce9f99d96b3182bfe7e88bb090ee").unwrap();
+
+        assert_eq!(info.title, "Pong");
+        assert_eq!(info.platform, "originalChip8");
+        assert_eq!(info.quirks, Quirks::CHIP8);
+        assert_eq!(info.tickrate, 15);
+        assert_eq!(info.keys["up"], 1);
+
+        assert_eq!(lookup("0000000000000000000000000000000000000000"), None);
+    }
+}
@@ -0,0 +1,601 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpListener,
+};
+
+use serde_json::{json, Value};
+
+use crate::{chip8::Chip8, symbols::Symbols};
+
+const NUM_KEYS: usize = 16;
+
+const EVENTS: [&str; 3] = ["frame", "sound", "video"];
+
+// JSON-RPC 2.0 error codes.
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+
+type RpcError = (i32, String);
+
+// Drives the emulator through JSON-RPC 2.0, one request per line. It only
+// runs when asked to, with "step":
+//
+//   -> {"jsonrpc": "2.0", "id": 1, "method": "step", "params": {"frames": 60}}
+//   <- {"jsonrpc": "2.0", "id": 1, "result": {"frame": 60, "pc": 522,
+//       "location": "main+0xA", "fault": null}}
+//
+// Addresses can be given as numbers or, with symbols loaded, as names like
+// "draw_paddle" or "draw_paddle+0x4".
+//
+// Methods:
+//
+//   load_rom {path}                   Load a rom, keeping the seed and quirks, and
+//                                     the symbol file next to it (ROM.sym) if any
+//   load_symbols {path}               Load a symbol file
+//   reset                             Restart the rom
+//   step {instructions | frames}      Run N instructions or frames
+//                                     -> {frame, pc, location: pc as a symbol,
+//                                         fault: null or why it halted}
+//   press {key}, release {key}        Press or release a CHIP-8 key (0-15)
+//   read_memory {address, length}     -> {data: [bytes]}
+//   write_memory {address, data}
+//   read_registers                    -> {v, i, pc, sp, stack, delay_timer, sound_timer}
+//   write_registers {v?, i?, pc?, delay_timer?, sound_timer?}
+//   framebuffer                       -> {cols, rows, pixels: [0 or 1, row by row]}
+//   save_state                        -> {state: hex}
+//   load_state {state}
+//   subscribe {events}, unsubscribe {events}
+//
+// Subscribed events are sent as "event" notifications before the response
+// to the step that caused them:
+//
+//   {"event": "frame", "frame": N}          A frame ended
+//   {"event": "sound", "frame": N, "on": b}  The buzzer started or stopped
+//   {"event": "video", "frame": N}          The screen changed during a frame
+pub struct Server {
+    chip8: Chip8,
+    iters_per_frame: u32,
+    // Instructions run in the current frame.
+    iter: u32,
+    frame: u64,
+    keys: [bool; NUM_KEYS],
+    // The screen at the start of the current frame.
+    last_video: Vec<u8>,
+    subscriptions: Vec<String>,
+    events: Vec<Value>,
+}
+
+impl Server {
+    pub fn init(chip8: Chip8, iters_per_frame: u32) -> Self {
+        Self {
+            last_video: chip8.video().to_vec(),
+            chip8,
+            iters_per_frame,
+            iter: 0,
+            frame: 0,
+            keys: [false; NUM_KEYS],
+            subscriptions: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    // Serves one client at a time, forever. A port number listens on
+    // localhost, anything else is the path of a Unix socket.
+    pub fn listen(&mut self, addr: &str) -> io::Result<()> {
+        if let Ok(port) = addr.parse::<u16>() {
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+
+            println!("RPC server listening on 127.0.0.1:{}...", port);
+
+            for stream in listener.incoming() {
+                let stream = stream?;
+                self.serve(BufReader::new(stream.try_clone()?), stream);
+            }
+
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::{fs::FileTypeExt, net::UnixListener};
+
+            // Left behind by a previous run. Other files are left alone, for
+            // bind to refuse.
+            if fs::symlink_metadata(addr).is_ok_and(|meta| meta.file_type().is_socket()) {
+                fs::remove_file(addr)?;
+            }
+
+            let listener = UnixListener::bind(addr)?;
+
+            println!("RPC server listening on {}...", addr);
+
+            for stream in listener.incoming() {
+                let stream = stream?;
+                self.serve(BufReader::new(stream.try_clone()?), stream);
+            }
+
+            Ok(())
+        }
+
+        #[cfg(not(unix))]
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Unix sockets are not supported, please inform a port.",
+        ))
+    }
+
+    fn serve(&mut self, reader: impl BufRead, mut writer: impl Write) {
+        println!("RPC client connected.");
+
+        for line in reader.lines() {
+            let Ok(line) = line else { break };
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let sent = self
+                .handle(&line)
+                .iter()
+                .try_for_each(|message| writeln!(writer, "{}", message))
+                .and_then(|_| writer.flush());
+
+            if sent.is_err() {
+                break;
+            }
+        }
+
+        println!("RPC client disconnected.");
+    }
+
+    // Answers a request, returning the event notifications it caused followed
+    // by the response, unless the request was a notification itself.
+    pub fn handle(&mut self, line: &str) -> Vec<String> {
+        let (id, result) = match serde_json::from_str::<Value>(line) {
+            Ok(request) => {
+                let params = request.get("params").unwrap_or(&Value::Null);
+                let result = match request.get("method").and_then(Value::as_str) {
+                    Some(method) => self.call(method, params),
+                    None => Err((INVALID_REQUEST, "Missing method.".to_string())),
+                };
+
+                (request.get("id").cloned(), result)
+            }
+            Err(e) => (Some(Value::Null), Err((PARSE_ERROR, e.to_string()))),
+        };
+
+        let mut messages: Vec<String> = self
+            .events
+            .drain(..)
+            .map(|event| json!({"jsonrpc": "2.0", "method": "event", "params": event}).to_string())
+            .collect();
+
+        if let Some(id) = id {
+            let response = match result {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                Err((code, message)) => json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {"code": code, "message": message},
+                }),
+            };
+
+            messages.push(response.to_string());
+        }
+
+        messages
+    }
+
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "load_rom" => {
+                let path = string(params, "path")?;
+                let rom = fs::read(path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+
+                let symbols = Symbols::for_rom(path).map_err(invalid)?;
+
+                let mut chip8 = Chip8::init(self.chip8.rng_seed());
+                chip8.set_quirks(self.chip8.quirks());
+                chip8.set_symbols(symbols.unwrap_or_default());
+                chip8.load(&rom).map_err(invalid)?;
+                self.chip8 = chip8;
+                self.restarted();
+            }
+            "load_symbols" => {
+                let symbols = Symbols::load(string(params, "path")?).map_err(invalid)?;
+                self.chip8.set_symbols(symbols);
+            }
+            "reset" => {
+                self.chip8.reset();
+                self.restarted();
+            }
+            "step" => {
+                if let Some(n) = optional_uint(params, "instructions")? {
+                    for _ in 0..n {
+                        self.step();
+                    }
+                } else {
+                    let frames = uint(params, "frames")?;
+                    let end = self
+                        .frame
+                        .checked_add(frames)
+                        .ok_or(invalid("Too many frames."))?;
+
+                    while self.frame < end {
+                        self.step();
+                    }
+                }
+
+                return Ok(json!({
+                    "frame": self.frame,
+                    "pc": self.chip8.pc(),
+                    "location": self.chip8.symbols().format(self.chip8.pc()),
+                    "fault": self.chip8.fault().map(|fault| fault.to_string()),
+                }));
+            }
+            "press" | "release" => {
+                let key = uint(params, "key")? as usize;
+
+                if key >= NUM_KEYS {
+                    return Err(invalid(format!("Invalid key: {}", key)));
+                }
+
+                self.keys[key] = method == "press";
+            }
+            "read_memory" => {
+                let range = self.mem_range(params, uint(params, "length")? as usize)?;
+
+                return Ok(json!({"data": &self.chip8.mem()[range]}));
+            }
+            "write_memory" => {
+                let data = bytes(params, "data")?;
+                let range = self.mem_range(params, data.len())?;
+
+                self.chip8.write_mem(range.start, &data).map_err(invalid)?;
+            }
+            "read_registers" => {
+                return Ok(json!({
+                    "v": self.chip8.regs(),
+                    "i": self.chip8.i(),
+                    "pc": self.chip8.pc(),
+                    "sp": self.chip8.sp(),
+                    "stack": self.chip8.stack(),
+                    "delay_timer": self.chip8.delay_timer(),
+                    "sound_timer": self.chip8.sound_timer(),
+                }));
+            }
+            "write_registers" => self.write_registers(params)?,
+            "framebuffer" => {
+                return Ok(json!({
+                    "cols": self.chip8.video_cols(),
+                    "rows": self.chip8.video_rows(),
+                    "pixels": self.chip8.video(),
+                }));
+            }
+            "save_state" => return Ok(json!({"state": to_hex(&self.chip8.save_state())})),
+            "load_state" => {
+                let state = from_hex(string(params, "state")?)?;
+                self.chip8.load_state(&state).map_err(invalid)?;
+                self.last_video = self.chip8.video().to_vec();
+            }
+            "subscribe" | "unsubscribe" => {
+                let events = params
+                    .get("events")
+                    .and_then(Value::as_array)
+                    .ok_or(invalid("Expected a list of events."))?;
+
+                for event in events {
+                    let event = event
+                        .as_str()
+                        .filter(|event| EVENTS.contains(event))
+                        .ok_or(invalid(format!("Unknown event: {}", event)))?;
+
+                    self.subscriptions.retain(|e| e != event);
+                    if method == "subscribe" {
+                        self.subscriptions.push(event.to_string());
+                    }
+                }
+            }
+            _ => return Err((METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+        }
+
+        Ok(Value::Null)
+    }
+
+    // Runs one instruction, as the main loop does: the timers count down and
+    // the vertical blank happens at the start of each frame.
+    fn step(&mut self) {
+        let sound = self.chip8.audio();
+
+        if self.iter == 0 {
+            self.chip8.update_timers();
+        }
+
+        self.chip8.write_keys(&self.keys);
+        self.chip8.set_vblank(self.iter == 0);
+        self.chip8.tick();
+
+        if self.chip8.audio() != sound {
+            self.event("sound", json!({"on": self.chip8.audio()}));
+        }
+
+        self.iter += 1;
+
+        if self.iter >= self.iters_per_frame {
+            self.iter = 0;
+            self.frame += 1;
+            self.event("frame", json!({}));
+
+            if self.chip8.video() != self.last_video {
+                self.last_video = self.chip8.video().to_vec();
+                self.event("video", json!({}));
+            }
+        }
+    }
+
+    fn restarted(&mut self) {
+        self.last_video = self.chip8.video().to_vec();
+        self.iter = 0;
+        self.frame = 0;
+    }
+
+    fn event(&mut self, name: &str, mut params: Value) {
+        if self.subscriptions.iter().any(|event| event == name) {
+            params["event"] = json!(name);
+            params["frame"] = json!(self.frame);
+            self.events.push(params);
+        }
+    }
+
+    fn write_registers(&mut self, params: &Value) -> Result<(), RpcError> {
+        let v = match params.get("v") {
+            Some(_) => Some(bytes(params, "v")?).filter(|v| v.len() == 16),
+            None => None,
+        };
+        let i = optional_uint(params, "i")?;
+        let pc = address(params, "pc", self.chip8.symbols())?;
+        let delay_t = optional_uint(params, "delay_timer")?;
+        let audio_t = optional_uint(params, "sound_timer")?;
+
+        if params.get("v").is_some() && v.is_none() {
+            return Err(invalid("Expected 16 values for v."));
+        }
+
+        let mem_size = self.chip8.mem().len() as u64;
+
+        if i.is_some_and(|i| i > 0xFFFF) || pc.is_some_and(|pc| pc >= mem_size - 1) {
+            return Err(invalid("Address out of range."));
+        }
+
+        if delay_t.is_some_and(|t| t > 0xFF) || audio_t.is_some_and(|t| t > 0xFF) {
+            return Err(invalid("Timers go up to 255."));
+        }
+
+        if let Some(v) = v {
+            for (x, value) in v.into_iter().enumerate() {
+                self.chip8.set_reg(x, value).map_err(invalid)?;
+            }
+        }
+
+        if let Some(i) = i {
+            self.chip8.set_i(i as u16);
+        }
+
+        if let Some(pc) = pc {
+            self.chip8.set_pc(pc as u16).map_err(invalid)?;
+        }
+
+        if let Some(delay_t) = delay_t {
+            self.chip8.set_delay_timer(delay_t as u8);
+        }
+
+        if let Some(audio_t) = audio_t {
+            self.chip8.set_sound_timer(audio_t as u8);
+        }
+
+        Ok(())
+    }
+
+    fn mem_range(&self, params: &Value, len: usize) -> Result<std::ops::Range<usize>, RpcError> {
+        let address = address(params, "address", self.chip8.symbols())?
+            .ok_or(invalid("Expected an address: address"))? as usize;
+
+        let end = address
+            .checked_add(len)
+            .filter(|&end| end <= self.chip8.mem().len())
+            .ok_or(invalid("Address out of range."))?;
+
+        Ok(address..end)
+    }
+}
+
+fn invalid(message: impl Into<String>) -> RpcError {
+    (INVALID_PARAMS, message.into())
+}
+
+fn string<'a>(params: &'a Value, name: &str) -> Result<&'a str, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or(invalid(format!("Expected a string: {}", name)))
+}
+
+fn uint(params: &Value, name: &str) -> Result<u64, RpcError> {
+    optional_uint(params, name)?.ok_or(invalid(format!("Expected a number: {}", name)))
+}
+
+fn optional_uint(params: &Value, name: &str) -> Result<Option<u64>, RpcError> {
+    match params.get(name) {
+        None => Ok(None),
+        Some(value) => value
+            .as_u64()
+            .map(Some)
+            .ok_or(invalid(format!("Expected a number: {}", name))),
+    }
+}
+
+// A number or a symbol.
+fn address(params: &Value, name: &str, symbols: &Symbols) -> Result<Option<u64>, RpcError> {
+    match params.get(name).and_then(Value::as_str) {
+        Some(text) => symbols
+            .resolve(text)
+            .map(|addr| Some(addr as u64))
+            .map_err(invalid),
+        None => optional_uint(params, name),
+    }
+}
+
+fn bytes(params: &Value, name: &str) -> Result<Vec<u8>, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_array)
+        .and_then(|values| {
+            values
+                .iter()
+                .map(|value| value.as_u64().and_then(|n| u8::try_from(n).ok()))
+                .collect()
+        })
+        .ok_or(invalid(format!("Expected a list of bytes: {}", name)))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>, RpcError> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid("Invalid hex string."));
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..(i + 2)], 16).map_err(|_| invalid("Invalid hex string."))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::Server;
+    use crate::{
+        chip8::{Chip8, Quirks},
+        symbols::Symbols,
+    };
+
+    fn call(server: &mut Server, method: &str, params: Value) -> Vec<Value> {
+        let request = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+
+        server
+            .handle(&request.to_string())
+            .iter()
+            .map(|message| serde_json::from_str(message).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_server() {
+        let mut chip8 = Chip8::init(0);
+        chip8.set_quirks(Quirks::XOCHIP);
+        // Waits for a key, then draws it and beeps.
+        chip8
+            .load16(&[0xF00A, 0xF029, 0xD015, 0xF018, 0x1208])
+            .unwrap();
+        chip8.set_symbols(Symbols::parse("main 0x200\nscore 0x300").unwrap());
+        let mut server = Server::init(chip8, 10);
+
+        call(
+            &mut server,
+            "subscribe",
+            json!({"events": ["video", "sound"]}),
+        );
+        call(&mut server, "press", json!({"key": 7}));
+        call(&mut server, "step", json!({"instructions": 3}));
+        call(&mut server, "release", json!({"key": 7}));
+
+        let messages = call(&mut server, "step", json!({"frames": 1}));
+        let events: Vec<_> = messages[..2]
+            .iter()
+            .map(|m| &m["params"]["event"])
+            .collect();
+        assert_eq!(events, [&json!("sound"), &json!("video")]);
+        assert_eq!(
+            messages[2]["result"],
+            json!({"frame": 1, "pc": 0x208, "location": "main+0x8", "fault": null})
+        );
+
+        let registers = &call(&mut server, "read_registers", Value::Null)[0]["result"];
+        assert_eq!(registers["v"][0], 7);
+        assert_eq!(registers["i"], 35);
+
+        let state = call(&mut server, "save_state", Value::Null)[0]["result"]["state"].clone();
+        call(
+            &mut server,
+            "write_memory",
+            json!({"address": "score", "data": [1, 2]}),
+        );
+        call(&mut server, "write_registers", json!({"i": 0x300}));
+        assert_eq!(
+            call(
+                &mut server,
+                "read_memory",
+                json!({"address": 0x2FF, "length": 3})
+            )[0]["result"],
+            json!({"data": [0, 1, 2]})
+        );
+
+        call(&mut server, "load_state", json!({"state": state}));
+        let framebuffer = &call(&mut server, "framebuffer", Value::Null)[0]["result"];
+        assert_eq!(framebuffer["pixels"][7], 1);
+        assert_eq!(
+            call(&mut server, "read_registers", Value::Null)[0]["result"]["i"],
+            35
+        );
+
+        let error = |messages: Vec<Value>| messages[0]["error"]["code"].clone();
+        assert_eq!(error(call(&mut server, "fly", Value::Null)), -32601);
+        assert_eq!(
+            error(call(&mut server, "write_registers", json!({"pc": "lives"}))),
+            -32602
+        );
+        assert_eq!(
+            error(call(&mut server, "press", json!({"key": 16}))),
+            -32602
+        );
+        assert_eq!(
+            error(call(
+                &mut server,
+                "read_memory",
+                json!({"address": 4095, "length": 2})
+            )),
+            -32602
+        );
+        assert_eq!(
+            error(call(
+                &mut server,
+                "read_memory",
+                json!({"address": 1, "length": u64::MAX})
+            )),
+            -32602
+        );
+        assert_eq!(
+            error(call(&mut server, "step", json!({"frames": u64::MAX}))),
+            -32602
+        );
+        assert_eq!(
+            error(call(&mut server, "load_state", json!({"state": "0é0"}))),
+            -32602
+        );
+        assert_eq!(
+            server.handle("{"),
+            [
+                r#"{"error":{"code":-32700,"message":"EOF while parsing an object at line 1 column 1"},"id":null,"jsonrpc":"2.0"}"#
+            ]
+        );
+    }
+}
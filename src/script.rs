@@ -0,0 +1,467 @@
+use std::{cell::RefCell, collections::HashSet, mem, ops::Range, rc::Rc};
+
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+
+use crate::{
+    chip8::{Chip8, StepOutcome},
+    dec_nibble, dec_x, dec_y,
+    overlay::Text,
+};
+
+const NUM_REGS: usize = 16;
+
+const NUM_KEYS: usize = 16;
+
+// Rhai scripts run by the emulator. They define any of these functions:
+//
+//     init()                 once, after the script is loaded, e.g. to
+//                            set up "this" and the watches
+//     on_frame(frame)        at the start of every frame
+//     on_pc(addr)            before an instruction at a watch_pc() address
+//     on_write(addr, value)  after Fx33 or Fx55 write to a watch_memory() range
+//     on_draw(x, y, n)       after a Dxyn draw, with the Vx and Vy values
+//
+// The functions share "this", a map for the script's own state, and can
+// read and write the emulator state, press keys and show text.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    this: Dynamic,
+    context: Rc<RefCell<Context>>,
+    hooks: Hooks,
+    // A failed script stops running, instead of repeating its error.
+    failed: bool,
+}
+
+// What the registered functions work on. The emulator is swapped in while a
+// hook runs.
+struct Context {
+    chip8: Chip8,
+    frame: u64,
+    keys: [bool; NUM_KEYS],
+    overlay: Vec<Text>,
+    pcs: HashSet<u16>,
+    writes: Vec<Range<usize>>,
+}
+
+// The hooks the script defines.
+struct Hooks {
+    frame: bool,
+    pc: bool,
+    write: bool,
+    draw: bool,
+}
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+impl Script {
+    pub fn load(path: &str) -> Result<Self, String> {
+        Script::init(|engine| engine.compile_file(path.into()).map_err(|e| e.to_string()))
+            .map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn compile(source: &str) -> Result<Self, String> {
+        Script::init(|engine| engine.compile(source).map_err(|e| e.to_string()))
+    }
+
+    fn init<F>(compile: F) -> Result<Self, String>
+    where
+        F: FnOnce(&Engine) -> Result<AST, String>,
+    {
+        let context = Rc::new(RefCell::new(Context {
+            chip8: Chip8::init(0),
+            frame: 0,
+            keys: [false; NUM_KEYS],
+            overlay: Vec::new(),
+            pcs: HashSet::new(),
+            writes: Vec::new(),
+        }));
+
+        let engine = engine(&context);
+        let ast = compile(&engine)?;
+
+        let defines = |name: &str, params: usize| {
+            ast.iter_functions()
+                .any(|f| f.name == name && f.params.len() == params)
+        };
+        let hooks = Hooks {
+            frame: defines("on_frame", 1),
+            pc: defines("on_pc", 1),
+            write: defines("on_write", 2),
+            draw: defines("on_draw", 3),
+        };
+        let has_init = defines("init", 0);
+
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| e.to_string())?;
+
+        let mut script = Self {
+            engine,
+            ast,
+            scope,
+            this: Map::new().into(),
+            context,
+            hooks,
+            failed: false,
+        };
+
+        if has_init {
+            script.call(&mut Chip8::init(0), "init", ())?;
+        }
+
+        Ok(script)
+    }
+
+    // Call at the start of each frame, before the instructions run. Text
+    // shown by the previous frame is cleared.
+    pub fn on_frame(&mut self, chip8: &mut Chip8, frame: u64) {
+        {
+            let mut context = self.context.borrow_mut();
+            context.frame = frame;
+            context.overlay.clear();
+        }
+
+        if self.hooks.frame {
+            self.hook(chip8, "on_frame", (frame as i64,));
+        }
+    }
+
+    // Runs an instruction, calling the hooks it triggers.
+    pub fn tick(&mut self, chip8: &mut Chip8) -> StepOutcome {
+        let pc = chip8.pc();
+
+        if self.hooks.pc && self.context.borrow().pcs.contains(&pc) {
+            self.hook(chip8, "on_pc", (pc as i64,));
+        }
+
+        // The hook may have moved on.
+        // Addresses wrap around at the end of memory, as in the core.
+        let mem_len = chip8.mem().len();
+        let pc = chip8.pc() as usize;
+        let opcode = (chip8.mem()[pc] as u16) << 8 | chip8.mem()[(pc + 1) % mem_len] as u16;
+        let x = dec_x!(opcode);
+        let i = chip8.i() as usize;
+
+        let draw = (opcode & 0xF000 == 0xD000).then(|| {
+            let regs = chip8.regs();
+            (regs[x], regs[dec_y!(opcode)], dec_nibble!(opcode))
+        });
+
+        let written = match opcode & 0xF0FF {
+            0xF033 => 3,
+            0xF055 => x + 1,
+            _ => 0,
+        };
+
+        let outcome = chip8.tick();
+
+        // Dxyn waiting for the vertical blank didn't draw yet.
+        if outcome != StepOutcome::Executed {
+            return outcome;
+        }
+
+        if let Some((x, y, n)) = draw.filter(|_| self.hooks.draw) {
+            self.hook(chip8, "on_draw", (x as i64, y as i64, n as i64));
+        }
+
+        if self.hooks.write {
+            let watched: Vec<_> = (i..i + written)
+                .map(|addr| addr % mem_len)
+                .filter(|addr| {
+                    self.context
+                        .borrow()
+                        .writes
+                        .iter()
+                        .any(|r| r.contains(addr))
+                })
+                .collect();
+
+            for addr in watched {
+                let value = chip8.mem()[addr];
+                self.hook(chip8, "on_write", (addr as i64, value as i64));
+            }
+        }
+
+        outcome
+    }
+
+    // The keypad with the keys the script holds down added.
+    pub fn keys(&self, keys: &[bool]) -> [bool; NUM_KEYS] {
+        let held = self.context.borrow().keys;
+
+        std::array::from_fn(|i| keys[i] || held[i])
+    }
+
+    // Text shown by the script this frame.
+    pub fn overlay(&self) -> Vec<Text> {
+        self.context.borrow().overlay.clone()
+    }
+
+    fn hook(&mut self, chip8: &mut Chip8, name: &str, args: impl FuncArgs) {
+        if self.failed {
+            return;
+        }
+
+        if let Err(msg) = self.call(chip8, name, args) {
+            println!("{}", msg);
+            self.failed = true;
+        }
+    }
+
+    fn call(&mut self, chip8: &mut Chip8, name: &str, args: impl FuncArgs) -> Result<(), String> {
+        mem::swap(chip8, &mut self.context.borrow_mut().chip8);
+
+        // Only the function runs, not the script's top level again.
+        let options = CallFnOptions::new()
+            .eval_ast(false)
+            .bind_this_ptr(&mut self.this);
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            options,
+            &mut self.scope,
+            &self.ast,
+            name,
+            args,
+        );
+
+        mem::swap(chip8, &mut self.context.borrow_mut().chip8);
+
+        result
+            .map(|_| ())
+            .map_err(|e| format!("Script error in {}(): {}", name, e))
+    }
+}
+
+fn engine(context: &Rc<RefCell<Context>>) -> Engine {
+    let mut engine = Engine::new();
+
+    let ctx = context.clone();
+    engine.register_fn("reg", move |x: i64| -> ScriptResult<i64> {
+        Ok(ctx.borrow().chip8.regs()[index(x, NUM_REGS, "register")?] as i64)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("set_reg", move |x: i64, value: i64| -> ScriptResult<()> {
+        let x = index(x, NUM_REGS, "register")?;
+        Ok(ctx.borrow_mut().chip8.set_reg(x, byte(value)?)?)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("index", move || ctx.borrow().chip8.i() as i64);
+
+    let ctx = context.clone();
+    engine.register_fn("set_index", move |i: i64| -> ScriptResult<()> {
+        let i = index(i, 0x10000, "index")?;
+        ctx.borrow_mut().chip8.set_i(i as u16);
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("pc", move || ctx.borrow().chip8.pc() as i64);
+
+    let ctx = context.clone();
+    engine.register_fn("set_pc", move |pc: i64| -> ScriptResult<()> {
+        let pc = index(pc, 0x10000, "address")?;
+        Ok(ctx.borrow_mut().chip8.set_pc(pc as u16)?)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("peek", move |addr: i64| -> ScriptResult<i64> {
+        let context = ctx.borrow();
+        let mem = context.chip8.mem();
+        Ok(mem[index(addr, mem.len(), "address")?] as i64)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("poke", move |addr: i64, value: i64| -> ScriptResult<()> {
+        let addr = index(addr, usize::MAX, "address")?;
+        Ok(ctx.borrow_mut().chip8.write_mem(addr, &[byte(value)?])?)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("delay_timer", move || {
+        ctx.borrow().chip8.delay_timer() as i64
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("set_delay_timer", move |value: i64| -> ScriptResult<()> {
+        ctx.borrow_mut().chip8.set_delay_timer(byte(value)?);
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("sound_timer", move || {
+        ctx.borrow().chip8.sound_timer() as i64
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("set_sound_timer", move |value: i64| -> ScriptResult<()> {
+        ctx.borrow_mut().chip8.set_sound_timer(byte(value)?);
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("pixel", move |x: i64, y: i64| -> ScriptResult<i64> {
+        let chip8 = &ctx.borrow().chip8;
+        let x = index(x, chip8.video_cols(), "column")?;
+        let y = index(y, chip8.video_rows(), "row")?;
+        Ok(chip8.video()[y * chip8.video_cols() + x] as i64)
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("frame", move || ctx.borrow().frame as i64);
+
+    let ctx = context.clone();
+    engine.register_fn("press", move |key: i64| -> ScriptResult<()> {
+        ctx.borrow_mut().keys[index(key, NUM_KEYS, "key")?] = true;
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("release", move |key: i64| -> ScriptResult<()> {
+        ctx.borrow_mut().keys[index(key, NUM_KEYS, "key")?] = false;
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("text", move |x: i64, y: i64, text: &str| {
+        ctx.borrow_mut().overlay.push(Text {
+            x: x as i32,
+            y: y as i32,
+            text: text.to_string(),
+        });
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("watch_pc", move |addr: i64| -> ScriptResult<()> {
+        let len = ctx.borrow().chip8.mem().len();
+        let addr = index(addr, len, "address")?;
+        ctx.borrow_mut().pcs.insert(addr as u16);
+        Ok(())
+    });
+
+    let ctx = context.clone();
+    engine.register_fn(
+        "watch_memory",
+        move |addr: i64, len: i64| -> ScriptResult<()> {
+            let mem_len = ctx.borrow().chip8.mem().len();
+            let start = index(addr, mem_len, "address")?;
+            let end = start + index(len, mem_len - start + 1, "length")?;
+            ctx.borrow_mut().writes.push(start..end);
+            Ok(())
+        },
+    );
+
+    engine
+}
+
+fn index(value: i64, len: usize, what: &str) -> ScriptResult<usize> {
+    usize::try_from(value)
+        .ok()
+        .filter(|&value| value < len)
+        .ok_or_else(|| format!("Invalid {}: {}", what, value).into())
+}
+
+fn byte(value: i64) -> ScriptResult<u8> {
+    u8::try_from(value).map_err(|_| format!("Invalid byte: {}", value).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Script;
+    use crate::{
+        chip8::{Chip8, Quirks},
+        overlay::Text,
+    };
+
+    #[test]
+    fn test_script() {
+        let mut script = Script::compile(
+            r#"
+            fn init() {
+                this.draws = 0;
+                watch_pc(0x206);
+                watch_memory(0x302, 1);
+            }
+
+            fn on_frame(frame) {
+                if frame == 1 {
+                    text(1, 2, "Hi");
+                }
+            }
+
+            fn on_pc(addr) {
+                set_reg(0, 7);
+                press(0xA);
+            }
+
+            fn on_write(addr, value) {
+                poke(0x400, value);
+            }
+
+            fn on_draw(x, y, n) {
+                this.draws += 1;
+                poke(0x401, x);
+                poke(0x402, this.draws);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut chip8 = Chip8::init(0);
+        chip8.set_quirks(Quirks::XOCHIP);
+        // LD V0, 5; LD I, 0x300; LD B, V0; DRW V0, V0, 1; JP 0x208
+        chip8
+            .load(&[0x60, 0x05, 0xA3, 0x00, 0xF0, 0x33, 0xD0, 0x01, 0x12, 0x08])
+            .unwrap();
+
+        script.on_frame(&mut chip8, 0);
+        for _ in 0..6 {
+            script.tick(&mut chip8);
+        }
+
+        assert_eq!(&chip8.mem()[0x400..0x403], &[5, 7, 1]);
+        assert!(script.keys(&[false; 16])[0xA]);
+        assert!(script.overlay().is_empty());
+
+        script.on_frame(&mut chip8, 1);
+        assert_eq!(
+            script.overlay(),
+            [Text {
+                x: 1,
+                y: 2,
+                text: "Hi".to_string()
+            }]
+        );
+
+        assert!(Script::compile("fn init() { peek(4096); }").is_err());
+
+        // Writes and fetches wrap around at the end of memory.
+        let mut script = Script::compile(
+            r#"
+            fn init() {
+                watch_memory(0, 1);
+            }
+
+            fn on_write(addr, value) {
+                poke(0x400, value);
+            }
+            "#,
+        )
+        .unwrap();
+
+        let mut chip8 = Chip8::init(0);
+        // LD I, 0xFFE; LD V0, 255; LD B, V0; JP 0xFFF
+        chip8
+            .load(&[0xAF, 0xFE, 0x60, 0xFF, 0xF0, 0x33, 0x1F, 0xFF])
+            .unwrap();
+
+        for _ in 0..5 {
+            script.tick(&mut chip8);
+        }
+
+        assert_eq!(chip8.mem()[0x400], 5);
+    }
+}
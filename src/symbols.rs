@@ -0,0 +1,192 @@
+use std::{collections::BTreeMap, fs, path::Path};
+
+use serde_json::Value;
+
+// Extension of the symbol file looked for next to a rom: pong.ch8 and
+// pong.sym.
+pub const EXTENSION: &str = "sym";
+
+// Names of rom addresses, from an assembler's symbol map or label file, shown
+// as "draw_paddle+0x4" instead of "0x2A4".
+//
+// Text files have a name and an address per line, in either order, e.g.
+// "draw_paddle 0x2A4", "0x2A4 draw_paddle", "draw_paddle = 0x2A4" or Octo's
+// ":const draw_paddle 0x2A4". Comments start with # or ;. JSON files map
+// names to addresses: {"draw_paddle": 676}.
+//
+// Octo's :const values are often not addresses at all (sprite sizes, key
+// codes), so they can be resolved by name but never label an address.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    names: BTreeMap<u16, String>,
+    addrs: BTreeMap<String, u16>,
+}
+
+impl Symbols {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+
+        Self::parse(&text).map_err(|msg| format!("{}: {}", path, msg))
+    }
+
+    // The symbol file next to a rom, if there is one.
+    pub fn for_rom(rom_path: &str) -> Result<Option<Self>, String> {
+        let path = Path::new(rom_path).with_extension(EXTENSION);
+
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Self::load(&path.to_string_lossy()).map(Some)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Self::default();
+
+        if text.trim_start().starts_with('{') {
+            let map: BTreeMap<String, Value> =
+                serde_json::from_str(text).map_err(|e| e.to_string())?;
+
+            for (name, value) in map {
+                let addr = match &value {
+                    Value::String(addr) => parse_addr(addr),
+                    value => value.as_u64().and_then(|addr| u16::try_from(addr).ok()),
+                };
+
+                symbols.insert(
+                    &name,
+                    addr.ok_or(format!("Invalid address: {}", value))?,
+                    true,
+                );
+            }
+
+            return Ok(symbols);
+        }
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split(['#', ';']).next().unwrap();
+            let is_const = line.trim_start().starts_with(":const");
+            let tokens: Vec<&str> = line
+                .split(|c: char| c.is_whitespace() || c == '=')
+                .map(|token| token.trim_end_matches(':'))
+                .filter(|token| !matches!(*token, "" | ":const"))
+                .collect();
+
+            let entry = match tokens[..] {
+                [] => continue,
+                [a, b] => parse_addr(a)
+                    .map(|addr| (b, addr))
+                    .or_else(|| parse_addr(b).map(|addr| (a, addr))),
+                _ => None,
+            };
+
+            let (name, addr) = entry.ok_or(format!("Invalid symbol on line {}.", n + 1))?;
+            symbols.insert(name, addr, !is_const);
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addrs.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.addrs.len()
+    }
+
+    // The symbol at an address exactly.
+    pub fn name(&self, addr: u16) -> Option<&str> {
+        self.names.get(&addr).map(String::as_str)
+    }
+
+    // The closest symbol at or before an address, plus the offset, or the
+    // address in hex if there is none.
+    pub fn format(&self, addr: u16) -> String {
+        self.locate(addr).unwrap_or(format!("0x{:03X}", addr))
+    }
+
+    // The closest symbol at or before an address, plus the offset.
+    pub fn locate(&self, addr: u16) -> Option<String> {
+        match self.names.range(..=addr).next_back()? {
+            (&start, name) if start == addr => Some(name.clone()),
+            (&start, name) => Some(format!("{}+0x{:X}", name, addr - start)),
+        }
+    }
+
+    // The address of "name", "name+0x4" or a number.
+    pub fn resolve(&self, text: &str) -> Result<u16, String> {
+        let (name, offset) = match text.split_once('+') {
+            Some((name, offset)) => (name.trim(), parse_addr(offset.trim())),
+            None => (text.trim(), Some(0)),
+        };
+
+        let offset = offset.ok_or(format!("Invalid offset: {}", text))?;
+
+        let addr = parse_addr(name).or_else(|| self.addrs.get(name).copied());
+
+        addr.and_then(|addr| addr.checked_add(offset))
+            .ok_or(format!("Unknown symbol: {}", name))
+    }
+
+    // Every name resolves, but only the first label given to an address is
+    // shown for it.
+    fn insert(&mut self, name: &str, addr: u16, is_label: bool) {
+        self.addrs.insert(name.to_string(), addr);
+
+        if is_label {
+            self.names.entry(addr).or_insert_with(|| name.to_string());
+        }
+    }
+}
+
+// "0x2A4", "$2A4" or "676".
+fn parse_addr(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbols;
+
+    #[test]
+    fn test_symbols() {
+        let symbols = Symbols::parse(
+            "# pong\n\
+             main 0x200\n\
+             0x2A4 draw_paddle\n\
+             :const score = 0x300 ; digits\n\
+             :const sprite_size 5\n\
+             paddle_loop 0x2A4\n\
+             \n",
+        )
+        .unwrap();
+
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(symbols.name(0x2A4), Some("draw_paddle"));
+        assert_eq!(symbols.format(0x200), "main");
+        assert_eq!(symbols.format(0x2A8), "draw_paddle+0x4");
+        assert_eq!(symbols.format(0x1FE), "0x1FE");
+        assert_eq!(symbols.locate(0x1FE), None);
+        assert_eq!(symbols.locate(0x202), Some("main+0x2".to_string()));
+        assert_eq!(symbols.name(0x300), None);
+        assert_eq!(symbols.format(0x301), "draw_paddle+0x5D");
+
+        assert_eq!(symbols.resolve("draw_paddle+0x4"), Ok(0x2A8));
+        assert_eq!(symbols.resolve("score + 2"), Ok(0x302));
+        assert_eq!(symbols.resolve("0x123"), Ok(0x123));
+        assert_eq!(symbols.resolve("paddle_loop"), Ok(0x2A4));
+        assert_eq!(symbols.resolve("sprite_size"), Ok(5));
+        assert!(symbols.resolve("ball").is_err());
+
+        let json = Symbols::parse(r#"{"main": 512, "ball": "0x2F0"}"#).unwrap();
+        assert_eq!(json.format(0x2F1), "ball+0x1");
+
+        assert!(Symbols::parse("main 0x200 extra").is_err());
+        assert!(Symbols::parse("main").is_err());
+    }
+}
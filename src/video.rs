@@ -1,4 +1,12 @@
-use sdl2::{pixels::Color, rect::Rect, render::WindowCanvas, VideoSubsystem};
+use sdl2::{
+    pixels::Color, rect::Rect, render::WindowCanvas, video::FullscreenType, VideoSubsystem,
+};
+
+use crate::{
+    filter::{Filter, FrameFilter},
+    overlay::Text,
+    palette::Palette,
+};
 
 const WINDOW_TITLE: &str = "lrchip8";
 
@@ -6,63 +14,166 @@ pub struct Video {
     canvas: WindowCanvas,
     cols: usize,
     scale_factor: usize,
-    bg_color: Color,
-    pxl_color: Color,
+    palette: Palette,
+    filter: FrameFilter,
+    overlay: Vec<Text>,
 }
 
 impl Video {
     pub fn init(
         video_subsystem: VideoSubsystem,
+        title: Option<&str>,
         cols: usize,
         rows: usize,
         scale_factor: usize,
-        bg_color: Color,
-        pxl_color: Color,
+        palette: Palette,
+        filter: Filter,
     ) -> Self {
         let width = (cols * scale_factor) as u32;
         let height = (rows * scale_factor) as u32;
 
         let window = video_subsystem
-            .window(WINDOW_TITLE, width, height)
+            .window(&window_title(title), width, height)
             .position_centered()
             .build()
             .unwrap();
 
-        let canvas = window.into_canvas().build().unwrap();
+        let mut canvas = window.into_canvas().build().unwrap();
+
+        // Keeps the aspect ratio, with black bars, on any screen size.
+        canvas.set_logical_size(width, height).unwrap();
 
         Self {
             canvas,
             cols,
             scale_factor,
-            bg_color,
-            pxl_color,
+            palette,
+            filter: FrameFilter::init(filter, cols * rows),
+            overlay: Vec::new(),
         }
     }
 
+    pub fn set_title(&mut self, title: Option<&str>) {
+        self.canvas
+            .window_mut()
+            .set_title(&window_title(title))
+            .unwrap();
+    }
+
+    pub fn set_fullscreen(&mut self, fullscreen: bool) {
+        let mode = if fullscreen {
+            FullscreenType::Desktop
+        } else {
+            FullscreenType::Off
+        };
+
+        self.canvas.window_mut().set_fullscreen(mode).unwrap();
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+    }
+
+    // Text shown over the screen from the next draw on, until replaced.
+    pub fn set_overlay(&mut self, overlay: Vec<Text>) {
+        self.overlay = overlay;
+    }
+
+    // Pixel values index the palette, so multi-plane output gets 4 colors.
     pub fn draw(&mut self, chip8_video: &[u8]) {
-        let rects = chip8_video
-            .iter()
-            .enumerate()
-            .filter_map(|(i, &pixel)| {
-                if pixel == 1 {
-                    Some(Rect::new(
-                        ((i % self.cols) * self.scale_factor) as i32,
-                        ((i / self.cols) * self.scale_factor) as i32,
-                        self.scale_factor as u32,
-                        self.scale_factor as u32,
-                    ))
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<Rect>>();
-
-        self.canvas.set_draw_color(self.bg_color);
+        let colors = self.palette.colors;
+        let pixels = self.filter.apply(chip8_video);
+
+        let mut rects = vec![Vec::new(); colors.len()];
+        let mut faded = Vec::new();
+
+        for (i, &(value, brightness)) in pixels.iter().enumerate() {
+            if value == 0 {
+                continue;
+            }
+
+            let rect = Rect::new(
+                ((i % self.cols) * self.scale_factor) as i32,
+                ((i / self.cols) * self.scale_factor) as i32,
+                self.scale_factor as u32,
+                self.scale_factor as u32,
+            );
+            let value = (value as usize).min(colors.len() - 1);
+
+            if brightness < 1.0 {
+                faded.push((blend(colors[0], colors[value], brightness), rect));
+            } else {
+                rects[value].push(rect);
+            }
+        }
+
+        self.canvas.set_draw_color(colors[0]);
         self.canvas.clear();
 
-        self.canvas.set_draw_color(self.pxl_color);
-        self.canvas.fill_rects(&rects).unwrap();
+        for (color, rects) in colors.iter().zip(&rects).skip(1) {
+            self.canvas.set_draw_color(*color);
+            self.canvas.fill_rects(rects).unwrap();
+        }
+
+        for (color, rect) in faded {
+            self.canvas.set_draw_color(color);
+            self.canvas.fill_rect(rect).unwrap();
+        }
+
+        self.draw_overlay();
 
         self.canvas.present();
     }
+
+    // Font pixels are half the size of the emulated ones, on a background
+    // box so the text stays readable over the game.
+    fn draw_overlay(&mut self) {
+        let colors = self.palette.colors;
+        let dot = (self.scale_factor / 2).max(1) as i32;
+
+        for text in &self.overlay {
+            let (width, height) = text.size();
+            let background = Rect::new(
+                (text.x - 1) * dot,
+                (text.y - 1) * dot,
+                (width as u32 + 2) * dot as u32,
+                (height as u32 + 2) * dot as u32,
+            );
+
+            let rects: Vec<_> = text
+                .pixels()
+                .iter()
+                .map(|(x, y)| {
+                    Rect::new(
+                        (text.x + x) * dot,
+                        (text.y + y) * dot,
+                        dot as u32,
+                        dot as u32,
+                    )
+                })
+                .collect();
+
+            self.canvas.set_draw_color(colors[0]);
+            self.canvas.fill_rect(background).unwrap();
+            self.canvas.set_draw_color(colors[1]);
+            self.canvas.fill_rects(&rects).unwrap();
+        }
+    }
+}
+
+fn blend(bg: Color, fg: Color, alpha: f32) -> Color {
+    let mix = |bg: u8, fg: u8| (bg as f32 + (fg as f32 - bg as f32) * alpha) as u8;
+
+    Color::RGB(mix(bg.r, fg.r), mix(bg.g, fg.g), mix(bg.b, fg.b))
+}
+
+fn window_title(title: Option<&str>) -> String {
+    match title {
+        Some(title) => format!("{} - {}", title, WINDOW_TITLE),
+        None => WINDOW_TITLE.to_string(),
+    }
 }
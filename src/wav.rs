@@ -0,0 +1,134 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::audio::{Beeper, Tone};
+
+const SAMPLE_RATE: u32 = 44100;
+const BITS_PER_SAMPLE: u16 = 16;
+const HEADER_SIZE: u32 = 44;
+
+// Renders the buzzer to a 16-bit mono WAV file from emulated time, so a
+// recording does not depend on the audio device or the host speed.
+pub struct WavRecorder {
+    writer: BufWriter<File>,
+    beeper: Beeper,
+    buzzer: bool,
+    start_time: f64,
+    samples: u64,
+    buf: Vec<f32>,
+}
+
+impl WavRecorder {
+    pub fn create<P: AsRef<Path>>(path: P, tone: Tone, start_time: f64) -> io::Result<Self> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        // Sizes are patched in when the recording finishes.
+        write_header(&mut writer, 0)?;
+
+        Ok(Self {
+            writer,
+            beeper: Beeper::init(tone, SAMPLE_RATE),
+            buzzer: false,
+            start_time,
+            samples: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    // Records the buzzer state at the given emulated time, in seconds.
+    pub fn set_buzzer(&mut self, on: bool, time: f64) -> io::Result<()> {
+        if on != self.buzzer {
+            self.sync(time)?;
+            self.buzzer = on;
+        }
+
+        Ok(())
+    }
+
+    // Renders everything up to the given emulated time.
+    pub fn sync(&mut self, time: f64) -> io::Result<()> {
+        let end = ((time - self.start_time) * SAMPLE_RATE as f64).round().max(0.0) as u64;
+
+        if end <= self.samples {
+            return Ok(());
+        }
+
+        self.buf.resize((end - self.samples) as usize, 0.0);
+        self.beeper.render(&mut self.buf, self.buzzer);
+
+        for &x in &self.buf {
+            let sample = (x.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&sample.to_le_bytes())?;
+        }
+
+        self.samples = end;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        let data_size = (self.samples * (BITS_PER_SAMPLE / 8) as u64) as u32;
+
+        self.writer.seek(SeekFrom::Start(0))?;
+        write_header(&mut self.writer, data_size)?;
+        self.writer.flush()
+    }
+}
+
+fn write_header<W: Write>(w: &mut W, data_size: u32) -> io::Result<()> {
+    let block_align = BITS_PER_SAMPLE / 8;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&1u16.to_le_bytes())?; // Mono
+    w.write_all(&SAMPLE_RATE.to_le_bytes())?;
+    w.write_all(&(SAMPLE_RATE * block_align as u32).to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_size.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::WavRecorder;
+    use crate::audio::Tone;
+
+    #[test]
+    fn test_wav_recorder() {
+        let path = env::temp_dir().join("lrchip8_test_wav_recorder.wav");
+        let mut rec = WavRecorder::create(&path, Tone::default(), 1.0).unwrap();
+
+        rec.set_buzzer(true, 1.5).unwrap();
+        rec.set_buzzer(false, 1.75).unwrap();
+        rec.sync(2.0).unwrap();
+        rec.finish().unwrap();
+
+        let wav = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let data_size = u32::from_le_bytes(wav[40..44].try_into().unwrap());
+        let sample = |secs: f64| {
+            let pos = 44 + (secs * 44100.0) as usize * 2;
+            i16::from_le_bytes(wav[pos..(pos + 2)].try_into().unwrap())
+        };
+
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(data_size, 44100 * 2);
+        assert_eq!(wav.len(), 44 + data_size as usize);
+        assert_eq!(sample(0.25), 0);
+        assert_ne!(sample(0.6), 0);
+        assert_eq!(sample(0.9), 0);
+    }
+}